@@ -0,0 +1,23 @@
+\set ON_ERROR_STOP on
+
+set local search_path to cpres, pg_catalog, public;
+
+-- emails sent by httpg once committed, with HTTPG_EMAIL_OUTBOX=cpres.email_outbox
+create table if not exists email_outbox (
+    id bigint generated always as identity primary key,
+    "from" text not null,
+    "to" text not null,
+    subject text not null,
+    html text not null,
+    status text not null default 'pending' check (status in ('pending', 'sent', 'failed')),
+    attempts int not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    last_error text,
+    sent_at timestamptz,
+    created_at timestamptz not null default now()
+);
+
+create index if not exists email_outbox_pending on email_outbox (next_attempt_at) where status = 'pending';
+
+-- /email inserts under the requester's role
+grant insert on email_outbox to anon, person;
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use deadpool_postgres::Pool;
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::header::ContentType, transport::smtp::authentication::Credentials
+};
+
+use crate::HttpgError;
+
+const BATCH: usize = 100;
+const MAX_BACKOFF: &str = "1 hour";
+
+pub type Mailer = AsyncSmtpTransport<Tokio1Executor>;
+
+pub fn mailer(relay: &str, user: &str, password: Option<&str>) -> Result<Mailer, HttpgError> {
+    let mut mailer = AsyncSmtpTransport::<Tokio1Executor>::from_url(relay)?;
+    if let Some(password) = password {
+        mailer = mailer.credentials(Credentials::new(user.to_owned(), password.to_owned()));
+    }
+    Ok(mailer.build())
+}
+
+pub fn message(sender: &str, from: &str, to: &str, subject: &str, html: &str) -> Result<Message, HttpgError> {
+    Ok(Message::builder()
+        .sender(sender.parse()?)
+        .from(from.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
+        .header(ContentType::TEXT_HTML)
+        .body(html.to_owned())?
+    )
+}
+
+/// An email may be sent twice if httpg stops between sending it and recording it.
+pub async fn deliver(pool: Pool, outbox: String, mailer: Mailer, sender: String, interval: Duration, max_attempts: i32) {
+    loop {
+        let mut sent = 0;
+        while sent < BATCH {
+            match deliver_one(&pool, &outbox, &mailer, &sender, max_attempts).await {
+                Ok(true) => sent = sent.saturating_add(1),
+                Ok(false) => break,
+                Err(e) => {
+                    tracing::warn!(outbox, "could not deliver emails: {e}");
+                    break;
+                },
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn deliver_one(pool: &Pool, outbox: &str, mailer: &Mailer, sender: &str, max_attempts: i32) -> Result<bool, HttpgError> {
+    let mut conn = pool.get().await?;
+    let tx = conn.transaction().await?;
+
+    let Some(row) = tx.query_opt(&format!(r#"
+        select id, "from", "to", subject, html
+        from {outbox}
+        where status = 'pending' and next_attempt_at <= now()
+        order by next_attempt_at
+        limit 1
+        for update skip locked
+    "#), &[]).await? else {
+        return Ok(false);
+    };
+    let id: i64 = row.try_get("id")?;
+
+    // an email that can't be built, like one with a bad address, won't be sent by retrying
+    let (sent, permanent) = match message(sender, row.try_get("from")?, row.try_get("to")?, row.try_get("subject")?, row.try_get("html")?) {
+        Ok(email) => (mailer.send(email).await.map(|_| ()).map_err(HttpgError::from), false),
+        Err(e) => (Err(e), true),
+    };
+
+    match sent {
+        Ok(()) => {
+            metrics::counter!("httpg_emails_total", "result" => "sent").increment(1);
+            tx.execute(&format!(r#"
+                update {outbox}
+                set status = 'sent', attempts = attempts + 1, sent_at = now(), last_error = null
+                where id = $1
+            "#), &[&id]).await?;
+        },
+        Err(e) => {
+            let failed = tx.query_one(&format!(r#"
+                update {outbox}
+                set attempts = attempts + 1,
+                    last_error = $2,
+                    status = case when $4 or attempts + 1 >= $3 then 'failed' else 'pending' end,
+                    next_attempt_at = now() + least(interval '{MAX_BACKOFF}', interval '1 second' * power(2, least(attempts, 20)))
+                where id = $1
+                returning status = 'failed'
+            "#), &[&id, &e.to_string(), &max_attempts, &permanent]).await?.try_get::<_, bool>(0)?;
+            tracing::warn!(outbox, id, failed, "could not send email: {e}");
+            metrics::counter!("httpg_emails_total", "result" => if failed { "failed" } else { "retry" }).increment(1);
+        },
+    }
+    tx.commit().await?;
+    Ok(true)
+}
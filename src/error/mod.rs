@@ -4,6 +4,8 @@ use deadpool_postgres::{CreatePoolError, PoolError};
 use http::StatusCode;
 use lettre::{address, transport};
 
+const RETRY_AFTER: &str = "1";
+
 #[derive(Debug, snafu::Snafu)]
 #[snafu(visibility(pub(crate)))]
 pub enum HttpgError {
@@ -13,11 +15,6 @@ pub enum HttpgError {
         backtrace: snafu::Backtrace,
     },
     #[snafu(transparent)]
-    SendError {
-        source: Box<tokio::sync::broadcast::error::SendError<tokio_postgres::Notification>>,
-        backtrace: snafu::Backtrace,
-    },
-    #[snafu(transparent)]
     Conf {
         source: conf::Error,
         backtrace: snafu::Backtrace,
@@ -63,6 +60,11 @@ pub enum HttpgError {
         backtrace: snafu::Backtrace,
     },
     #[snafu(transparent)]
+    AxumBody {
+        source: axum::Error,
+        backtrace: snafu::Backtrace,
+    },
+    #[snafu(transparent)]
     AxumHeaderName {
         source: header::InvalidHeaderName,
         backtrace: snafu::Backtrace,
@@ -132,6 +134,26 @@ pub enum HttpgError {
         source: http::header::ToStrError,
         backtrace: snafu::Backtrace,
     },
+    #[snafu(transparent)]
+    Tls {
+        source: rustls::Error,
+        backtrace: snafu::Backtrace,
+    },
+    #[snafu(transparent)]
+    TlsVerifier {
+        source: rustls::client::VerifierBuilderError,
+        backtrace: snafu::Backtrace,
+    },
+    #[snafu(transparent)]
+    Pem {
+        source: rustls::pki_types::pem::Error,
+        backtrace: snafu::Backtrace,
+    },
+    #[snafu(transparent)]
+    Metrics {
+        source: metrics_exporter_prometheus::BuildError,
+        backtrace: snafu::Backtrace,
+    },
     WebPushPrivateKey,
     #[snafu(display("refused query: {query}\nReason: {reason:?}"))]
     RefusedSql {
@@ -143,6 +165,35 @@ pub enum HttpgError {
         i: usize,
         param: String,
     },
+    #[snafu(display("invalid order[{rel}]: {reason}"))]
+    InvalidOrder {
+        rel: String,
+        reason: String,
+    },
+    #[snafu(display("invalid page[{rel}]: {reason}"))]
+    InvalidPage {
+        rel: String,
+        reason: String,
+    },
+    #[snafu(display("query plan too expensive: cost {cost} (max {max_cost:?}), rows {rows} (max {max_rows:?})"))]
+    PlanTooExpensive {
+        cost: f64,
+        rows: f64,
+        max_cost: Option<f64>,
+        max_rows: Option<f64>,
+    },
+    #[snafu(display("not allowed to listen on {channel}"))]
+    ForbiddenChannel {
+        channel: String,
+    },
+    #[snafu(display("statement_timeout should be a positive number of milliseconds"))]
+    InvalidStatementTimeout,
+    #[snafu(display("invalid live query: {reason}"))]
+    InvalidLive {
+        reason: String,
+    },
+    #[snafu(display("too many concurrent requests"))]
+    Saturated,
     #[snafu(display("column should be bytea or text, {type_} given"))]
     InvalidColType {
         type_: postgres_types::Type,
@@ -173,8 +224,17 @@ impl IntoResponse for HttpgError {
         if let Some(b) = snafu::ErrorCompat::backtrace(&self) {
             tracing::error!("{b}");
         }
+        if matches!(self, Self::Saturated | Self::Deadpool { source: PoolError::Timeout(_), .. }) {
+            return (StatusCode::SERVICE_UNAVAILABLE, [(header::RETRY_AFTER, RETRY_AFTER)], "service unavailable").into_response();
+        }
+        let status = match self {
+            Self::InvalidOrder {..} | Self::InvalidPage {..} | Self::InvalidLive {..} | Self::InvalidStatementTimeout => StatusCode::BAD_REQUEST,
+            Self::PlanTooExpensive {..} => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::ForbiddenChannel {..} => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
         let r = snafu::Report::from_error(self);
 
-        (StatusCode::INTERNAL_SERVER_ERROR, r.to_string()).into_response()
+        (status, r.to_string()).into_response()
     }
 }
@@ -1,10 +1,10 @@
 
-use std::{collections::BTreeMap, ops::Not};
+use std::{collections::BTreeMap, ops::{ControlFlow, Not}};
 
 use cookie::Cookie;
 use axum::{
     Json, extract::{FromRequest, Multipart, Request}, http::{
-        StatusCode, header::{ACCEPT, ACCEPT_LANGUAGE, CONTENT_TYPE, HOST, REFERER, ORIGIN}
+        StatusCode, header::{ACCEPT, ACCEPT_LANGUAGE, CONTENT_TYPE, HOST, REFERER, ORIGIN}, HeaderName
     }, response::{IntoResponse, Response}
 };
 use axum::extract::FromRef;
@@ -13,7 +13,7 @@ use postgres_types::{to_sql_checked, ToSql};
 use serde::{Deserialize, Serialize};
 use sqlparser::{ast::{Visit, VisitMut}, dialect::PostgreSqlDialect, parser::Parser};
 
-use crate::{HttpgError, sql::{AllowList, VisitOrderBy}};
+use crate::{HttpgError, sql::{AllowList, CountBase, Keyset, KeysetBase, Order, OrderKey, Page, VisitOrderBy, VisitPage}};
 
 
 #[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -107,6 +107,13 @@ impl ToSql for Param {
     to_sql_checked!();
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Count {
+    Exact,
+    Estimated,
+}
+
 #[derive(Debug, Default, Deserialize, PartialEq, Clone)]
 pub struct QueryPart {
     pub sql: Option<String>,
@@ -116,8 +123,11 @@ pub struct QueryPart {
     pub redirect: Option<String>,
     pub cache_control: Option<String>,
     pub order: Option<BTreeMap<String, serde_json::Value>>,
+    pub order_mode: Option<String>,
+    pub page: Option<BTreeMap<String, serde_json::Value>>,
     pub on_error: Option<String>,
     pub use_primary: Option<String>,
+    pub statement_timeout: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
@@ -144,6 +154,16 @@ pub struct Query {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub order: Option<BTreeMap<String, serde_json::Value>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<BTreeMap<String, Page>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefer_count: Option<Count>,
+    #[serde(skip)]
+    pub count_sql: Option<String>,
+    #[serde(skip)]
+    pub count: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub on_error: Option<String>,
     #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
     pub qs: serde_json::Map<String, serde_json::Value>,
@@ -151,6 +171,8 @@ pub struct Query {
     pub body: serde_json::Map<String, serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub use_primary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statement_timeout: Option<u64>,
 }
 
 impl<S> FromRequest<S> for Query
@@ -250,9 +272,70 @@ where
         let origin = headers.get(ORIGIN).and_then(|value| value.to_str().ok()).map(str::to_string);
 
         let order = qs.order.to_owned().or(body.order.to_owned());
+        let order_keys = order.as_ref()
+            .map(|order| order.iter()
+                .map(|(rel, value)| Ok((rel.to_owned(), OrderKey::parse(rel, value)?)))
+                .collect::<Result<Order, HttpgError>>()
+            )
+            .transpose()
+            .map_err(|e| e.into_response())?
+        ;
+        let order_mode = qs.order_mode.to_owned().or(body.order_mode.to_owned());
+        let append = match order_mode.as_deref() {
+            None | Some("replace") => false,
+            Some("append") => true,
+            Some(_) => return Err((StatusCode::BAD_REQUEST, "order_mode should be replace or append").into_response()),
+        };
+
+        let page = qs.page.to_owned().or(body.page.to_owned())
+            .map(|page| page.iter().map(|(rel, value)| {
+                let mut page = Page::parse(rel, value)?;
+                let Some(limit) = page.limit else {
+                    return Ok((rel.to_owned(), page));
+                };
+                let mut next = raw_qs.to_owned();
+                let mut pages = next.get("page").and_then(serde_json::Value::as_object).cloned().unwrap_or_default();
+                let mut rel_page = pages.get(rel).and_then(serde_json::Value::as_object).cloned().unwrap_or_default();
+                // ordered pages continue after their last row, the others at the next offset
+                let keyset = page.offset.is_none() && order_keys.as_ref().is_some_and(|order| order.contains_key(rel));
+                match keyset {
+                    true => rel_page.remove("after"),
+                    false => rel_page.insert("offset".into(), page.offset.unwrap_or_default().saturating_add(limit).into()),
+                };
+                pages.insert(rel.to_owned(), rel_page.into());
+                next.insert("page".into(), pages.into());
+                let next = format!("{}?{}", uri.path(), serde_qs::to_string(&next)?);
+                match keyset {
+                    true => page.keyset = Some(Keyset { sql: String::new(), next }),
+                    false => page.next = Some(next),
+                }
+                Ok((rel.to_owned(), page))
+            }).collect::<Result<BTreeMap<_, _>, HttpgError>>())
+            .transpose()
+            .map_err(|e| e.into_response())?
+        ;
+
+        let prefer_count = headers.get_all(HeaderName::from_static("prefer")).iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split([',', ';']))
+            .find_map(|pref| match pref.trim() {
+                "count=exact" => Some(Count::Exact),
+                "count=estimated" => Some(Count::Estimated),
+                _ => None,
+            })
+        ;
 
         let sql = qs.sql.or(body.sql);
-        let sql = parse_sql(&order, sql)?;
+        let count_sql = sql.to_owned().filter(|_| prefer_count.is_some());
+        let sql = app_state.sql_cache.parse(sql, &order_keys, append, &page, |sql| parse_sql(&order_keys, append, &page, sql))?;
+        let count_sql = count_sql.and_then(|sql| count_base(&page, &sql));
+        let page = page.map(|page| page.into_iter().map(|(rel, mut page)| {
+            page.keyset = page.keyset.take().and_then(|keyset| Some(Keyset {
+                sql: keyset_base(&rel, order_keys.as_ref()?.get(&rel)?, sql.as_deref()?)?,
+                ..keyset
+            }));
+            (rel, page)
+        }).collect());
 
         let referer_header = headers.get(REFERER);
         let referer = referer_header.and_then(|value| value.to_str().ok());
@@ -264,7 +347,7 @@ where
         };
 
         let on_error = qs.on_error.to_owned().or(body.on_error.to_owned());
-        let on_error = parse_sql(&order, on_error)?;
+        let on_error = app_state.sql_cache.parse(on_error, &order_keys, append, &page, |sql| parse_sql(&order_keys, append, &page, sql))?;
 
         let params: Result<Vec<Param>, HttpgError> = qs.params.to_owned()
             .unwrap_or_default()
@@ -303,9 +386,21 @@ where
 
         let use_primary = qs.use_primary.or(body.use_primary);
 
+        let statement_timeout = match qs.statement_timeout.or(body.statement_timeout) {
+            None => None,
+            Some(serde_json::Value::Number(ms)) if let Some(ms) = ms.as_u64().filter(|ms| *ms > 0) => Some(ms),
+            Some(serde_json::Value::String(ms)) if let Ok(ms) = ms.parse() && ms > 0 => Some(ms),
+            Some(_) => return Err(HttpgError::InvalidStatementTimeout.into_response()),
+        };
+
         Ok(Self {
             sql,
             order,
+            order_mode,
+            page,
+            prefer_count,
+            count_sql,
+            count: None,
             cookies: BTreeMap::from_iter(
                 headers.get_all("cookie").iter().map(|c| {
                     let c = Cookie::parse(c.to_str()?)?;
@@ -318,13 +413,13 @@ where
             params,
             files,
             qs: raw_qs.into_iter().filter_map(|(key, value)|
-                ["sql", "on_error", "accept", "content_type", "in_types", "redirect", "cache_control", "order", "use_primary"]
+                ["sql", "on_error", "accept", "content_type", "in_types", "redirect", "cache_control", "order", "order_mode", "page", "use_primary", "statement_timeout"]
                     .contains(&key.as_str())
                     .not()
                     .then_some((key, value))
             ).collect(),
             body: raw_body.into_iter().filter_map(|(key, value)|
-                ["sql", "on_error", "accept", "content_type", "in_types", "redirect", "cache_control", "order", "use_primary"]
+                ["sql", "on_error", "accept", "content_type", "in_types", "redirect", "cache_control", "order", "order_mode", "page", "use_primary", "statement_timeout"]
                     .contains(&key.as_str())
                     .not()
                     .then_some((key, value))
@@ -338,11 +433,12 @@ where
             cache_control,
             on_error,
             use_primary,
+            statement_timeout,
         })
     }
 }
 
-fn parse_sql(order: &Option<BTreeMap<String, serde_json::Value>>, root_sql: Option<String>) -> Result<Option<String>, HttpgError> {
+fn parse_sql(order: &Option<Order>, append: bool, page: &Option<BTreeMap<String, Page>>, root_sql: Option<String>) -> Result<Option<String>, HttpgError> {
     let sql = match &root_sql {
         Some(sql) => match Parser::parse_sql(&PostgreSqlDialect{}, sql.as_str()) {
             Ok(mut statements) => {
@@ -353,8 +449,15 @@ fn parse_sql(order: &Option<BTreeMap<String, serde_json::Value>>, root_sql: Opti
                     return allowlist.0.map(|_| root_sql.clone());
                 }
 
-                if let Some(order) = order.to_owned() {
-                    let _ = VisitMut::visit(&mut statements, &mut VisitOrderBy(order));
+                if let Some(order) = order {
+                    let _ = VisitMut::visit(&mut statements, &mut VisitOrderBy { order, append });
+                }
+                if let Some(page) = page
+                    && let ControlFlow::Break(e) = VisitMut::visit(&mut statements, &mut VisitPage { page, order: order.as_ref() })
+                {
+                    return Err(e);
+                }
+                if order.is_some() || page.is_some() {
                     Ok(statements.first().map(|s|s.to_string()))
                 }
                 else {Ok(Some(sql.to_string()))}
@@ -367,6 +470,20 @@ fn parse_sql(order: &Option<BTreeMap<String, serde_json::Value>>, root_sql: Opti
     Ok(sql)
 }
 
+fn count_base(page: &Option<BTreeMap<String, Page>>, sql: &str) -> Option<String> {
+    let statements = Parser::parse_sql(&PostgreSqlDialect{}, sql).ok()?;
+    let mut count = CountBase { page: page.as_ref(), base: None };
+    let _ = Visit::visit(&statements, &mut count);
+    count.sql()
+}
+
+fn keyset_base(rel: &str, keys: &[OrderKey], sql: &str) -> Option<String> {
+    let statements = Parser::parse_sql(&PostgreSqlDialect{}, sql).ok()?;
+    let mut keyset = KeysetBase { rel, keys, base: None };
+    let _ = Visit::visit(&statements, &mut keyset);
+    keyset.sql()
+}
+
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 #[allow(clippy::indexing_slicing)]
@@ -377,8 +494,7 @@ use axum::{body::Body, http::{header::CONTENT_TYPE, Request}};
 
     use axum::extract::FromRequest;
     use conf::Conf;
-use tokio_postgres::Notification;
-    use crate::{extract::query::{Param, Query}};
+    use crate::{extract::query::{Param, Query}, postgres::listen::Listener, sql::cache::SqlCache};
 
     #[tokio::test]
     async fn test_json_body() {
@@ -389,19 +505,19 @@ use tokio_postgres::Notification;
 
         let httpg_config = crate::HttpgConfig::parse();
 
-        let read_pool = httpg_config.pg.read_pool().unwrap();
-        let write_pool = httpg_config.pg.write_pool().unwrap();
-
-        let (client, mut _conn) = httpg_config.pg.connect().await.unwrap();
+        let tls = httpg_config.pg.tls().unwrap();
+        let write_pool = httpg_config.pg.write_pool(tls.clone()).unwrap();
+        let read_pool = Arc::new(httpg_config.pg.read_pool(tls.clone(), write_pool.clone()).unwrap());
 
-        let (tx, _rx) = tokio::sync::broadcast::channel::<Notification>(16);
-    
         let state = crate::AppState {
             read_pool,
             write_pool,
+            tls,
             config: httpg_config.to_owned(),
-            tx,
-            client: Arc::new(client),
+            listener: Arc::new(Listener::new(16)),
+            sql_cache: Arc::new(SqlCache::new(httpg_config.sql_cache_size)),
+            metrics: None,
+            limiter: None,
         };
         let q = Query::from_request(req, &state).await.unwrap();
 
@@ -419,19 +535,19 @@ use tokio_postgres::Notification;
 
         let httpg_config = crate::HttpgConfig::parse();
 
-        let read_pool = httpg_config.pg.read_pool().unwrap();
-        let write_pool = httpg_config.pg.write_pool().unwrap();
-    
-        let (client, mut _conn) = httpg_config.pg.connect().await.unwrap();
+        let tls = httpg_config.pg.tls().unwrap();
+        let write_pool = httpg_config.pg.write_pool(tls.clone()).unwrap();
+        let read_pool = Arc::new(httpg_config.pg.read_pool(tls.clone(), write_pool.clone()).unwrap());
 
-        let (tx, _rx) = tokio::sync::broadcast::channel::<Notification>(16);
-    
         let state = crate::AppState {
             read_pool,
             write_pool,
+            tls,
             config: httpg_config.to_owned(),
-            tx,
-            client: Arc::new(client),
+            listener: Arc::new(Listener::new(16)),
+            sql_cache: Arc::new(SqlCache::new(httpg_config.sql_cache_size)),
+            metrics: None,
+            limiter: None,
         };
         let q = Query::from_request(req, &state).await.unwrap();
 
@@ -1,3 +1,4 @@
+mod email;
 mod error;
 mod extract;
 mod sql;
@@ -6,9 +7,9 @@ mod postgres;
 
 use http::Uri;
 use axum::{
-    Router, extract::{DefaultBodyLimit, Path, State}, http::{
+    Json, Router, extract::{DefaultBodyLimit, FromRequest, Path, State, ws::{self, WebSocket, WebSocketUpgrade}}, http::{
         StatusCode, header::SET_COOKIE,
-    }, response::{IntoResponse, NoContent, Redirect, Sse, sse::Event}, routing::{get, post}
+    }, response::{IntoResponse, NoContent, Redirect, Sse, sse::{Event, KeepAlive}}, routing::{get, post}
 };
 use axum_extra::extract::cookie::Cookie;
 use axum_server::tls_rustls::RustlsConfig;
@@ -17,22 +18,24 @@ use conf::Conf;
 
 use cookie::time::{Duration, OffsetDateTime};
 use futures::{StreamExt, TryStreamExt};
-use lettre::{
-    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor, message::header::ContentType, transport::smtp::authentication::{Credentials}
-};
+use lettre::AsyncTransport;
 use serde_json::json;
-use tokio::sync::{broadcast::Sender};
+use tokio::sync::{OnceCell, Semaphore};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tower::builder::ServiceBuilder;
 use tower_http::{cors::{Any, CorsLayer}, services::ServeDir, trace::TraceLayer};
 use web_push::{ContentEncoding, HyperWebPushClient, SubscriptionInfo, VapidSignatureBuilder, WebPushClient, WebPushMessageBuilder};
 use std::{env, fs::{self, File}, net::{SocketAddr, TcpListener}, sync::Arc};
-use std::collections::HashMap;
-use tokio_postgres::{AsyncMessage, Client, IsolationLevel, Notification, types::Type};
-use deadpool_postgres::Pool;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tokio_postgres::{AsyncMessage, IsolationLevel, Transaction, types::{ToSql, Type}};
+use deadpool_postgres::{Pool, PoolError, TimeoutType};
+use tokio_postgres_rustls::MakeRustlsConnect;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use biscuit_auth::{KeyPair, PrivateKey, Biscuit, builder::*};
 
-use crate::{error::HttpgError, postgres::{PostgresConfig, QueryGuard}, response::CancelStream};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+use crate::{error::HttpgError, postgres::{PostgresConfig, QueryGuard, changes::{self, Filter}, listen::{Broadcast, Listener}, replica::ReadPool}, response::CancelStream, sql::cache::SqlCache};
 
 #[derive(Clone, Conf)]
 struct TlsConfig {
@@ -58,6 +61,12 @@ struct HttpgConfig {
     #[conf(long, env)]
     smtp_relay: String,
     #[conf(long, env)]
+    email_outbox: Option<String>,
+    #[conf(long, env, default_value="1000")]
+    email_outbox_interval: u64,
+    #[conf(long, env, default_value="10")]
+    email_max_attempts: i32,
+    #[conf(long, env)]
     anon_role: String,
     #[conf(long, env)]
     index_sql: String,
@@ -69,17 +78,52 @@ struct HttpgConfig {
     tls: Option<TlsConfig>,
     #[conf(long, env, default_value="public")]
     public_dir: String,
+    #[conf(long, env, default_value="5")]
+    read_your_writes_window: i64,
+    #[conf(long, env, default_value="200")]
+    read_your_writes_wait: u64,
+    #[conf(long, env, default_value="5000")]
+    failover_wait: u64,
+    #[conf(long, env, default_value="1024")]
+    sql_cache_size: usize,
+    #[conf(flag, long, env)]
+    metrics: bool,
+    #[conf(long, env)]
+    max_plan_cost: Option<f64>,
+    #[conf(long, env)]
+    max_plan_rows: Option<f64>,
+    #[conf(long, env)]
+    max_statement_timeout: Option<u64>,
+    #[conf(long, env)]
+    sse_auth_sql: Option<String>,
+    #[conf(long, env)]
+    sse_replay_sql: Option<String>,
+    #[conf(long, env)]
+    sse_fetch_sql: Option<String>,
+    #[conf(long, env, default_value="15")]
+    sse_keep_alive: u64,
+    #[conf(long, env, default_value="1024")]
+    sse_capacity: std::num::NonZeroUsize,
+    #[conf(long, env)]
+    changes_slot: Option<String>,
+    #[conf(long, env)]
+    max_concurrent_requests: Option<usize>,
+    #[conf(long, env, default_value="1000")]
+    max_queue_wait: u64,
     #[conf(flatten, prefix="pg")]
     pg: PostgresConfig,
 }
 
 #[derive(Clone)]
 struct AppState {
-    read_pool: Pool,
+    read_pool: Arc<ReadPool>,
     write_pool: Pool,
+    tls: MakeRustlsConnect,
     config: HttpgConfig,
-    tx: Sender<Notification>,
-    client: Arc<Client>,
+    listener: Arc<Listener>,
+    sql_cache: Arc<SqlCache>,
+    metrics: Option<PrometheusHandle>,
+    limiter: Option<Arc<Semaphore>>,
 }
 
 #[tokio::main]
@@ -94,34 +138,37 @@ async fn main() -> Result<(), HttpgError> {
 
     let httpg_config = HttpgConfig::parse();
 
-    let read_pool = httpg_config.pg.read_pool()?;
-    let write_pool = httpg_config.pg.write_pool()?;
-
-    let (client, mut conn) = httpg_config.pg.connect().await?;
-    let (tx, _rx) = tokio::sync::broadcast::channel::<Notification>(16);
-    let mut stream = futures::stream::poll_fn(move |cx| conn.poll_message(cx));
-    let wrapped_tx = tx.clone();
-    tokio::spawn(async move {
-        while let Some(Ok(m)) = stream.next().await {
-            match m {
-                tokio_postgres::AsyncMessage::Notice(n) => {
-                    tracing::info!("{n:#?}");
-                },
-                tokio_postgres::AsyncMessage::Notification(n) => {
-                    wrapped_tx.send(n).map_err(Box::new)?;
-                }
-                _ => {}
-            }
-        }
-        Ok::<_, HttpgError>(())
-    });
-    
+    let tls = httpg_config.pg.tls()?;
+    let write_pool = httpg_config.pg.write_pool(tls.clone())?;
+    let read_pool = Arc::new(httpg_config.pg.read_pool(tls.clone(), write_pool.clone())?);
+    tokio::spawn(read_pool.clone().watch(httpg_config.pg.health_check_interval()));
+
+    let listener = Arc::new(Listener::new(httpg_config.sse_capacity.get()));
+    tokio::spawn(listener.clone().supervise(httpg_config.pg.clone(), tls.clone()));
+    if let Some(slot) = &httpg_config.changes_slot {
+        tokio::spawn(changes::consume(httpg_config.pg.clone(), tls.clone(), slot.clone(), listener.changes.clone()));
+    }
+
+    if let Some(outbox) = &httpg_config.email_outbox {
+        let mailer = email::mailer(&httpg_config.smtp_relay, &httpg_config.smtp_user, httpg_config.smtp_password.as_deref())?;
+        let interval = std::time::Duration::from_millis(httpg_config.email_outbox_interval);
+        tokio::spawn(email::deliver(write_pool.clone(), outbox.clone(), mailer, httpg_config.smtp_sender.clone(), interval, httpg_config.email_max_attempts));
+    }
+
+    let metrics = match httpg_config.metrics {
+        true => Some(PrometheusBuilder::new().install_recorder()?),
+        false => None,
+    };
+
     let state = AppState {
         read_pool,
         write_pool,
+        tls,
         config: httpg_config.to_owned(),
-        tx,
-        client: Arc::new(client),
+        listener,
+        sql_cache: Arc::new(SqlCache::new(httpg_config.sql_cache_size)),
+        metrics,
+        limiter: httpg_config.max_concurrent_requests.map(|permits| Arc::new(Semaphore::new(permits))),
     };
 
     let app = Router::new()
@@ -130,6 +177,12 @@ async fn main() -> Result<(), HttpgError> {
         .route("/logout", get(logout).post(logout))
         .route("/{path}/logout", get(logout).post(logout))
         .route("/sse/{channel}", get(sse_query))
+        .route("/sse/changes/{table}", get(sse_changes))
+        .route("/sse/live", get(sse_live))
+        .route("/{path}/sse/live", get(sse_live))
+        .route("/{path}/sse/changes/{table}", get(sse_changes))
+        .route("/ws", get(ws))
+        .route("/{path}/ws", get(ws))
         .route("/{path}/sse/{channel}", get(sse_query))
         .route("/query", get(stream_query).post(post_query))
         .route("/{path}/query", get(stream_query).post(post_query))
@@ -142,6 +195,9 @@ async fn main() -> Result<(), HttpgError> {
         .route("/{path}/webpush", get(web_push).post(web_push))
         .route("/login", get(login).post(login))
         .route("/{path}/login", get(login).post(login))
+        .route_layer(axum::middleware::from_fn_with_state(state.to_owned(), limit))
+        .route("/metrics", get(render_metrics))
+        .route("/metrics/channels", get(render_channels))
         .fallback_service(ServeDir::new(httpg_config.public_dir))
         .with_state(state.to_owned())
         .layer(ServiceBuilder::new()
@@ -234,6 +290,48 @@ async fn index(
     stream_query(state.to_owned(), biscuit, None, query).await
 }
 
+async fn limit(
+    State(AppState {limiter, config: HttpgConfig {max_queue_wait, ..}, ..}): State<AppState>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Result<axum::response::Response, HttpgError> {
+    let Some(limiter) = limiter else {
+        return Ok(next.run(req).await);
+    };
+    let queued = std::time::Instant::now();
+    let depth = metrics::gauge!("httpg_queue_depth");
+    depth.increment(1);
+    let permit = tokio::time::timeout(std::time::Duration::from_millis(max_queue_wait), limiter.acquire_owned()).await;
+    depth.decrement(1);
+    metrics::histogram!("httpg_queue_wait_seconds").record(queued.elapsed());
+
+    let Ok(Ok(_permit)) = permit else {
+        metrics::counter!("httpg_requests_rejected_total").increment(1);
+        return Err(HttpgError::Saturated);
+    };
+    Ok(next.run(req).await)
+}
+
+#[debug_handler]
+async fn render_metrics(
+    State(AppState {metrics, read_pool, ..}): State<AppState>,
+) -> Result<impl IntoResponse, HttpgError> {
+    read_pool.record_metrics();
+    Ok(match metrics {
+        Some(handle) => handle.render().into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+async fn render_channels(
+    State(AppState {metrics, listener, ..}): State<AppState>,
+) -> Result<impl IntoResponse, HttpgError> {
+    Ok(match metrics {
+        Some(_) => Json(listener.channels().await).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
 #[debug_handler]
 async fn login(
     State(AppState {write_pool, config: HttpgConfig { login_query, tls, anon_role, private_key, ..}, ..}): State<AppState>,
@@ -250,7 +348,7 @@ async fn login(
     ;
 
     tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
-    tx.batch_execute(&pre(&biscuit, &anon_role)).await?;
+    tx.batch_execute(&pre(&biscuit, &anon_role)?).await?;
 
     let sql_params: Vec<(_, Type)> = query.params.iter().map(|param| {
         (param.tosql_sync(), param.to_owned().into())
@@ -299,20 +397,153 @@ async fn logout(
     ))
 }
 
-fn pre(biscuit: &Option<extract::biscuit::Biscuit>, anon_role: &String) -> String {
+fn pre(biscuit: &Option<extract::biscuit::Biscuit>, anon_role: &String) -> Result<String, HttpgError> {
 
     let mut s = vec![
         format!("set local role to {anon_role}"),
     ];
 
-    s.push(biscuit.to_owned().map(|b| b.0.join(";")).unwrap_or_default());
+    let biscuit = biscuit.to_owned().map(|b| b.0.join(";")).unwrap_or_default();
+    sql::set_local_only(&biscuit)?;
+    s.push(biscuit);
+
+    Ok(s.join(";"))
+}
+
+/// Biscuits can override the limits per role with `set local httpg.max_plan_cost to ...`.
+async fn guard_plan(
+    tx: &Transaction<'_>,
+    sql: &str,
+    sql_params: &[(&(dyn ToSql + Sync), Type)],
+    max_cost: Option<f64>,
+    max_rows: Option<f64>,
+) -> Result<(), HttpgError> {
+    if (max_cost.is_none() && max_rows.is_none()) || !sql::explainable(sql) {
+        return Ok(());
+    }
+    let settings = tx.query_one(
+        "select nullif(current_setting('httpg.max_plan_cost', true), '')::float8, nullif(current_setting('httpg.max_plan_rows', true), '')::float8",
+        &[],
+    ).await?;
+    let max_cost = settings.try_get::<_, Option<f64>>(0)?.or(max_cost);
+    let max_rows = settings.try_get::<_, Option<f64>>(1)?.or(max_rows);
+
+    let rows = tx.query_typed(&format!("explain (format json) {sql}"), sql_params).await?;
+    let plan: Option<serde_json::Value> = rows.first().map(|row| row.try_get(0)).transpose()?;
+    let estimate = |key: &str| plan.as_ref()
+        .and_then(|plan| plan.pointer(&format!("/0/Plan/{key}")))
+        .and_then(serde_json::Value::as_f64)
+        .unwrap_or_default()
+    ;
+    let (cost, rows) = (estimate("Total Cost"), estimate("Plan Rows"));
+
+    if max_cost.is_some_and(|max| cost > max) || max_rows.is_some_and(|max| rows > max) {
+        return Err(HttpgError::PlanTooExpensive { cost, rows, max_cost, max_rows });
+    }
+    Ok(())
+}
+
+/// Applies the requested `statement_timeout` (ms), capped by the role's `httpg.max_statement_timeout`
+/// (`alter role ... set`, or a biscuit's `set local`) or else by `max`, and without either never raised above the current one.
+async fn statement_timeout(
+    tx: &Transaction<'_>,
+    requested: Option<u64>,
+    max: Option<u64>,
+) -> Result<(), HttpgError> {
+    let Some(requested) = requested else {
+        return Ok(());
+    };
+    // 0 would disable it
+    if requested == 0 {
+        return Err(HttpgError::InvalidStatementTimeout);
+    }
+    let row = tx.query_one(
+        "with max as (
+            select coalesce(
+                (select split_part(setting, '=', 2) from pg_db_role_setting, unnest(setconfig) setting
+                where setrole = current_user::regrole and setdatabase in (0, (select oid from pg_database where datname = current_database()))
+                and setting like 'httpg.max_statement_timeout=%' order by setdatabase desc limit 1),
+                nullif(current_setting('httpg.max_statement_timeout', true), '')
+            ) as max
+        )
+        select case when max ~ '^[0-9]+$' then max::int8 else (extract(epoch from max::interval) * 1000)::int8 end,
+            (select setting::int8 from pg_settings where name = 'statement_timeout')
+        from max",
+        &[],
+    ).await?;
+    let role_max: Option<i64> = row.try_get(0)?;
+    let current: i64 = row.try_get(1)?;
+
+    let max = role_max.and_then(|max| u64::try_from(max).ok()).or(max).filter(|max| *max > 0);
+    let timeout = match (max, u64::try_from(current).unwrap_or_default()) {
+        (Some(max), _) => requested.min(max),
+        (None, 0) => requested,
+        (None, current) => requested.min(current),
+    };
+
+    tx.query_typed_raw("select set_config('statement_timeout', $1, true)", [(timeout.to_string(), Type::TEXT)]).await?;
+    Ok(())
+}
 
-    s.join(";")
+async fn count(
+    tx: &Transaction<'_>,
+    query: &extract::query::Query,
+    sql_params: &[(&(dyn ToSql + Sync), Type)],
+    max_plan_cost: Option<f64>,
+    max_plan_rows: Option<f64>,
+) -> Result<Option<i64>, HttpgError> {
+    let Some(base) = &query.count_sql else {
+        return Ok(None);
+    };
+    match query.prefer_count {
+        Some(extract::query::Count::Exact) => {
+            // the base has no limit, so it may cost a lot more than the query
+            let sql = format!("select count(*) from ({base}) as httpg_count");
+            guard_plan(tx, &sql, sql_params, max_plan_cost, max_plan_rows).await?;
+            let rows = tx.query_typed(&sql, sql_params).await?;
+            Ok(rows.first().map(|row| row.try_get(0)).transpose()?)
+        },
+        Some(extract::query::Count::Estimated) => {
+            let rows = tx.query_typed(&format!("explain (format json) {base}"), sql_params).await?;
+            let plan: Option<serde_json::Value> = rows.first().map(|row| row.try_get(0)).transpose()?;
+            Ok(plan.and_then(|plan| plan.pointer("/0/Plan/Plan Rows").and_then(serde_json::Value::as_i64)))
+        },
+        None => Ok(None),
+    }
+}
+
+async fn keyset_next(
+    tx: &Transaction<'_>,
+    rel: &str,
+    limit: u64,
+    keyset: &sql::Keyset,
+    sql_params: &[(&(dyn ToSql + Sync), Type)],
+) -> Result<Option<String>, HttpgError> {
+    // the order keys may not be selectable from every query, which mustn't fail it
+    tx.batch_execute("savepoint httpg_keyset").await?;
+    let rows = match tx.query_typed(&keyset.sql, sql_params).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            tracing::warn!(rel, "could not link to the next page: {e}");
+            tx.batch_execute("rollback to savepoint httpg_keyset").await?;
+            return Ok(None);
+        },
+    };
+    let last = match rows.last() {
+        Some(last) if u64::try_from(rows.len()).ok() == Some(limit) => last,
+        _ => return Ok(None),
+    };
+    let after: Option<Vec<String>> = (0..last.len()).map(|i| last.try_get(i)).collect::<Result<_, _>>()?;
+    // a null can't be passed as after
+    let Some(after) = after else {
+        return Ok(None);
+    };
+    Ok(Some(format!("{}&{}", keyset.next, serde_qs::to_string(&json!({"page": {rel: {"after": after}}}))?)))
 }
 
 #[debug_handler]
 async fn email(
-    State(AppState {write_pool, config: HttpgConfig { smtp_sender, smtp_user, smtp_password, smtp_relay, anon_role, ..}, ..}): State<AppState>,
+    State(AppState {write_pool, tls, config: HttpgConfig { smtp_sender, smtp_user, smtp_password, smtp_relay, email_outbox, anon_role, ..}, ..}): State<AppState>,
     biscuit: Option<extract::biscuit::Biscuit>,
     query: extract::query::Query,
 ) -> Result<impl IntoResponse, HttpgError> {
@@ -323,43 +554,49 @@ async fn email(
         .start().await
     ?;
 
-    let _guard = QueryGuard {
+    let mut guard = QueryGuard {
         cancel_token: tx.cancel_token(),
+        tls: tls.clone(),
         finished: false,
     };
-    tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
-    tx.batch_execute(&pre(&biscuit, &anon_role)).await?;
-
-    let sql_params: Vec<(_, Type)> = query.params.iter().map(|param| {
-        (param.tosql_sync(), param.to_owned().into())
-    }).collect();
-
-    if let Some(sql) = query.sql.as_ref() {
-        let rows = tx.query_typed_raw(sql, sql_params).await?;
-
-        let mut mailer = AsyncSmtpTransport::<Tokio1Executor>::from_url(&smtp_relay)?;
+    guard.check(async {
+        tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
+        tx.batch_execute(&pre(&biscuit, &anon_role)?).await?;
+
+        let sql_params: Vec<(_, Type)> = query.params.iter().map(|param| {
+            (param.tosql_sync(), param.to_owned().into())
+        }).collect();
+
+        if let Some(sql) = query.sql.as_ref() {
+            match email_outbox {
+                // sent once committed, by the outbox worker
+                Some(outbox) => {
+                    // read whole, as the connection can't take the inserts while rows are still streamed
+                    let rows = tx.query_typed(sql, &sql_params).await?;
+                    let insert = format!(r#"insert into {outbox} ("from", "to", subject, html) values ($1, $2, $3, $4)"#);
+                    for row in &rows {
+                        let (from, to, subject, html) = (row.get::<&str, &str>("from"), row.get::<&str, &str>("to"), row.get::<&str, &str>("subject"), row.get::<&str, &str>("html"));
+                        // invalid addresses are refused now rather than retried
+                        email::message(&smtp_sender, from, to, subject, html)?;
+                        tx.execute(&insert, &[&from, &to, &subject, &html]).await?;
+                    }
+                },
+                None => {
+                    let rows = tx.query_typed_raw(sql, sql_params).await?;
+                    let mailer = email::mailer(&smtp_relay, &smtp_user, smtp_password.as_deref())?;
+                    rows.err_into::<HttpgError>().try_for_each(async |row| {
+                        let email = email::message(&smtp_sender, row.get::<&str, &str>("from"), row.get::<&str, &str>("to"), row.get::<&str, &str>("subject"), row.get::<&str, &str>("html"))?;
+                        mailer.send(email).await?;
+                        Ok(())
+                    }).await?;
+                },
+            }
 
-        if let Some(smtp_password) = smtp_password {
-            let creds = Credentials::new(smtp_user, smtp_password);
-            mailer = mailer.credentials(creds);
+            tx.commit().await?;
         }
-        let mailer = mailer.build();
-
-        rows.err_into::<HttpgError>().try_for_each(async |row| {
-            let email = Message::builder()
-                .sender(smtp_sender.parse()?)
-                .from(row.get::<&str, &str>("from").parse()?)
-                .to(row.get::<&str, &str>("to").parse()?)
-                .subject(row.get::<&str, &str>("subject"))
-                .header(ContentType::TEXT_HTML)
-                .body(row.get::<&str, &str>("html").to_string())
-            ?;
-            mailer.send(email).await?;
-            Ok(())
-        }).await?;
-
-        tx.commit().await?;
-    }
+        Ok::<_, HttpgError>(())
+    }).await?;
+    guard.finished = true;
 
     Ok(query.redirect.as_ref()
         .map(|r| Redirect::to(r).into_response())
@@ -408,7 +645,7 @@ async fn email(
 
 #[debug_handler]
 async fn web_push(
-    State(AppState {read_pool, config: HttpgConfig { anon_role, webpush_private_key_file, ..}, ..}): State<AppState>,
+    State(AppState {read_pool, tls, config: HttpgConfig { anon_role, webpush_private_key_file, ..}, ..}): State<AppState>,
     biscuit: Option<extract::biscuit::Biscuit>,
     query: extract::query::Query,
 ) -> Result<impl IntoResponse, HttpgError> {
@@ -419,50 +656,55 @@ async fn web_push(
         .start().await
     ?;
 
-    let _guard = QueryGuard {
+    let mut guard = QueryGuard {
         cancel_token: tx.cancel_token(),
+        tls: tls.clone(),
         finished: false,
     };
-    tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
-    tx.batch_execute(&pre(&biscuit, &anon_role)).await?;
+    let n = guard.check(async {
+        tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
+        tx.batch_execute(&pre(&biscuit, &anon_role)?).await?;
 
-    let sql_params: Vec<(_, Type)> = query.params.iter().map(|param| {
-        (param.tosql_sync(), param.to_owned().into())
-    }).collect();
+        let sql_params: Vec<(_, Type)> = query.params.iter().map(|param| {
+            (param.tosql_sync(), param.to_owned().into())
+        }).collect();
 
-    let n = if let Some(sql) = query.sql.as_ref() {
-        let rows = tx.query_typed_raw(sql, sql_params).await?;
+        let n = if let Some(sql) = query.sql.as_ref() {
+            let rows = tx.query_typed_raw(sql, sql_params).await?;
 
-        let client = HyperWebPushClient::new();
+            let client = HyperWebPushClient::new();
 
-        let private_key = File::open(webpush_private_key_file.as_ref().ok_or(HttpgError::WebPushPrivateKey)?)?;
+            let private_key = File::open(webpush_private_key_file.as_ref().ok_or(HttpgError::WebPushPrivateKey)?)?;
 
-        let n = rows.err_into::<HttpgError>().try_fold(0, async |acc, row| {
-            let subscription_info = SubscriptionInfo::new(
-                row.get::<&str, &str>("endpoint"),
-                row.get::<&str, &str>("p256dh"),
-                row.get::<&str, &str>("auth"),
-            );
+            let n = rows.err_into::<HttpgError>().try_fold(0, async |acc, row| {
+                let subscription_info = SubscriptionInfo::new(
+                    row.get::<&str, &str>("endpoint"),
+                    row.get::<&str, &str>("p256dh"),
+                    row.get::<&str, &str>("auth"),
+                );
 
-            let mut builder = WebPushMessageBuilder::new(&subscription_info);
-            builder.set_payload(ContentEncoding::Aes128Gcm, row.get::<&str, &[u8]>("content"));
+                let mut builder = WebPushMessageBuilder::new(&subscription_info);
+                builder.set_payload(ContentEncoding::Aes128Gcm, row.get::<&str, &[u8]>("content"));
 
-            let sig_builder = VapidSignatureBuilder::from_pem(
-                &private_key,
-                &subscription_info
-            )?.build()?;
+                let sig_builder = VapidSignatureBuilder::from_pem(
+                    &private_key,
+                    &subscription_info
+                )?.build()?;
 
-            builder.set_vapid_signature(sig_builder);
+                builder.set_vapid_signature(sig_builder);
 
-            client.send(builder.build()?).await?;
-            Ok(acc + 1)
-        }).await;
+                client.send(builder.build()?).await?;
+                Ok(acc + 1)
+            }).await;
 
-        tx.commit().await?;
-        n
-    } else {
-        Ok(0)
-    };
+            tx.commit().await?;
+            n
+        } else {
+            Ok(0)
+        };
+        Ok::<_, HttpgError>(n)
+    }).await?;
+    guard.finished = true;
 
     let redirect = query.redirect.as_deref().unwrap_or("/").parse::<Uri>()?;
     let serde_qs = serde_qs::Config::new().max_depth(0).use_form_encoding(true);
@@ -493,16 +735,17 @@ async fn web_push(
 
 #[debug_handler]
 async fn stream_query(
-    State(AppState {read_pool, write_pool, config: HttpgConfig {anon_role, ..}, ..}): State<AppState>,
+    State(AppState {read_pool, write_pool, tls, config: HttpgConfig {anon_role, max_plan_cost, max_plan_rows, max_statement_timeout, read_your_writes_wait, ..}, ..}): State<AppState>,
     biscuit: Option<extract::biscuit::Biscuit>,
     _path: Option<Path<String>>,
-    query: extract::query::Query,
+    mut query: extract::query::Query,
 ) -> Result<impl IntoResponse, HttpgError> {
 
-    let mut conn = match query.use_primary {
-        Some(_) => write_pool,
-        None => read_pool,
-    }.get().await?;
+    let mut conn = match (&query.use_primary, query.cookies.get("httpg_lsn")) {
+        (Some(_), _) => write_pool.get().await?,
+        (None, Some(lsn)) => read_pool.get_after(lsn, std::time::Duration::from_millis(read_your_writes_wait)).await?,
+        (None, None) => read_pool.get().await?,
+    };
 
     let tx = conn.build_transaction()
         .read_only(true)
@@ -510,23 +753,49 @@ async fn stream_query(
         .start().await?
     ;
 
-    let guard = QueryGuard {
+    let mut guard = QueryGuard {
         cancel_token: tx.cancel_token(),
+        tls: tls.clone(),
         finished: false,
     };
 
-    tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
-    tx.batch_execute(&pre(&biscuit, &anon_role)).await?;
-
-    let sql_params: Vec<(_, Type)> = query.params.iter().map(|param| {
+    // cloned, so that the count and the keyset pages can still be written to query
+    let params = query.params.clone();
+    let sql_params: Vec<(_, Type)> = params.iter().map(|param| {
         (param.tosql_sync(), param.to_owned().into())
     }).collect();
 
+    guard.check(async {
+        tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
+        tx.query_typed_raw("select set_config('httpg.page', $1, true)", [(serde_json::to_string(&query.page)?, Type::TEXT)]).await?;
+        tx.batch_execute(&pre(&biscuit, &anon_role)?).await?;
+        statement_timeout(&tx, query.statement_timeout, max_statement_timeout).await?;
+
+        if let Some(sql) = &query.sql {
+            guard_plan(&tx, sql, &sql_params, max_plan_cost, max_plan_rows).await?;
+        }
+
+        query.count = count(&tx, &query, &sql_params, max_plan_cost, max_plan_rows).await?;
+        if let Some(count) = query.count {
+            tx.query_typed_raw("select set_config('httpg.count', $1, true)", [(count.to_string(), Type::TEXT)]).await?;
+        }
+
+        for (rel, page) in query.page.iter_mut().flatten() {
+            if let (Some(keyset), Some(limit)) = (&page.keyset, page.limit) {
+                guard_plan(&tx, &keyset.sql, &sql_params, max_plan_cost, max_plan_rows).await?;
+                page.next = keyset_next(&tx, rel, limit, keyset, &sql_params).await?;
+            }
+        }
+        // again, now that the keyset pages know their next one
+        tx.query_typed_raw("select set_config('httpg.page', $1, true)", [(serde_json::to_string(&query.page)?, Type::TEXT)]).await?;
+        Ok::<_, HttpgError>(())
+    }).await?;
+
     let rows = match &query.sql {
         Some(sql) => if query.body.contains_key("stream") {
-            CancelStream::new(tx.query_typed_raw(sql, sql_params).await?, guard)
+            CancelStream::new(guard.check(tx.query_typed_raw(sql, sql_params)).await?, guard)
         } else {
-            CancelStream::from_vec(tx.query_typed(sql, &sql_params).await?, guard)
+            CancelStream::from_vec(guard.check(tx.query_typed(sql, &sql_params)).await?, guard)
         },
         None => CancelStream::from_vec(vec![], guard),
     };
@@ -537,35 +806,513 @@ async fn stream_query(
     })
 }
 
+async fn authorize_channel(
+    read_pool: &ReadPool,
+    biscuit: &Option<extract::biscuit::Biscuit>,
+    anon_role: &String,
+    sql: &str,
+    channel: &str,
+) -> Result<(), HttpgError> {
+    let mut conn = read_pool.get().await?;
+    let tx = conn.build_transaction().read_only(true).start().await?;
+    tx.batch_execute(&pre(biscuit, anon_role)?).await?;
+
+    let rows = tx.query_typed(sql, &[(&channel, Type::TEXT)]).await?;
+    match rows.first().map(|row| row.try_get::<_, Option<bool>>(0)).transpose()?.flatten() {
+        Some(true) => Ok(()),
+        _ => Err(HttpgError::ForbiddenChannel { channel: channel.to_owned() }),
+    }
+}
+
+async fn replay_events(
+    read_pool: &ReadPool,
+    biscuit: &Option<extract::biscuit::Biscuit>,
+    anon_role: &String,
+    sql: &str,
+    channel: &str,
+    last_event_id: &str,
+) -> Result<Vec<String>, HttpgError> {
+    let mut conn = read_pool.get().await?;
+    let tx = conn.build_transaction().read_only(true).start().await?;
+    tx.batch_execute(&pre(biscuit, anon_role)?).await?;
+
+    tx.query_typed(sql, &[(&channel, Type::TEXT), (&last_event_id, Type::TEXT)]).await?
+        .iter()
+        .map(|row| Ok(row.try_get(0)?))
+        .collect()
+}
+
+/// Read as the anonymous role, since it's shared by every subscriber.
+async fn fetch_payload(
+    read_pool: &ReadPool,
+    anon_role: &String,
+    sql: Option<&str>,
+    channel: &str,
+    reference: &str,
+) -> Result<Option<String>, HttpgError> {
+    let mut conn = read_pool.get().await?;
+    let tx = conn.build_transaction().read_only(true).start().await?;
+    tx.batch_execute(&pre(&None, anon_role)?).await?;
+
+    let rows = match sql {
+        Some(sql) => tx.query_typed(sql, &[(&channel, Type::TEXT), (&reference, Type::TEXT)]).await?,
+        None => {
+            let Some((table, id)) = reference.rsplit_once(':') else {
+                return Err(HttpgError::anyhow(format!("invalid ref {reference}, should be <table>:<id>")));
+            };
+            let relation = table.split('.').map(|part| sqlparser::ast::Ident::with_quote('"', part).to_string()).collect::<Vec<_>>().join(".");
+            tx.query_typed(&format!("select jsonb_build_object('data', to_jsonb(r))::text from {relation} r where r.id::text = $1"), &[(&id, Type::TEXT)]).await?
+        },
+    };
+    Ok(rows.first().map(|row| row.try_get(0)).transpose()?)
+}
+
+async fn resolve_payload<'a>(
+    read_pool: &ReadPool,
+    anon_role: &String,
+    fetch_sql: Option<&str>,
+    channel: &str,
+    payload: &'a str,
+    fetched: &'a OnceCell<String>,
+) -> Result<&'a str, HttpgError> {
+    let Some(reference) = response::sse::reference(payload) else {
+        return Ok(payload);
+    };
+    let fetched = fetched.get_or_try_init(|| async {
+        Ok::<_, HttpgError>(fetch_payload(read_pool, anon_role, fetch_sql, channel, &reference).await?.unwrap_or_else(|| payload.to_owned()))
+    }).await?;
+    Ok(fetched)
+}
+
+struct SseRender {
+    read_pool: Arc<ReadPool>,
+    tls: MakeRustlsConnect,
+    biscuit: Option<extract::biscuit::Biscuit>,
+    anon_role: String,
+    query: extract::query::Query,
+    channel: String,
+    fetch_sql: Option<String>,
+    max_plan_cost: Option<f64>,
+    max_plan_rows: Option<f64>,
+}
+
+impl SseRender {
+    async fn event(&self, payload: &str, fetched: &OnceCell<String>) -> (Option<String>, Event) {
+        let payload = match resolve_payload(&self.read_pool, &self.anon_role, self.fetch_sql.as_deref(), &self.channel, payload, fetched).await {
+            Ok(payload) => payload,
+            Err(e) => return (None, Event::default().event("error").data(e.to_string())),
+        };
+        let Some(sql) = &self.query.sql else {
+            return response::sse::event(payload, None);
+        };
+        match self.render(sql, payload).await {
+            Ok(rendered) => response::sse::event(payload, Some(rendered)),
+            Err(e) => (None, Event::default().event("error").data(e.to_string())),
+        }
+    }
+
+    async fn render(&self, sql: &str, payload: &str) -> Result<String, HttpgError> {
+        let mut conn = self.read_pool.get().await?;
+        let tx = conn.build_transaction()
+            .read_only(true)
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .start().await?
+        ;
+        let mut guard = QueryGuard {
+            cancel_token: tx.cancel_token(),
+            tls: self.tls.clone(),
+            finished: false,
+        };
+        let params: [(&(dyn ToSql + Sync), Type); 1] = [(&payload, Type::TEXT)];
+        let rows = guard.check(async {
+            tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&self.query)?, Type::TEXT)]).await?;
+            tx.batch_execute(&pre(&self.biscuit, &self.anon_role)?).await?;
+            guard_plan(&tx, sql, &params, self.max_plan_cost, self.max_plan_rows).await?;
+            Ok::<_, HttpgError>(tx.query_typed(sql, &params).await?)
+        }).await?;
+        response::sse::render(CancelStream::from_vec(rows, guard)).await
+    }
+}
+
 #[debug_handler]
 async fn sse_query(
-    State(AppState {tx, client, ..}): State<AppState>,
+    State(AppState {listener, read_pool, tls, config: HttpgConfig {anon_role, sse_auth_sql, sse_replay_sql, sse_fetch_sql, sse_keep_alive, max_plan_cost, max_plan_rows, ..}, ..}): State<AppState>,
+    biscuit: Option<extract::biscuit::Biscuit>,
+    headers: http::HeaderMap,
     Path(channel): Path<String>,
+    query: extract::query::Query,
+) -> Result<impl IntoResponse, HttpgError> {
+
+    if let Some(sql) = sse_auth_sql {
+        authorize_channel(&read_pool, &biscuit, &anon_role, &sql, &channel).await?;
+    }
+
+    let rx = listener.tx.subscribe();
+    let subscription = listener.subscribe(&channel).await?;
+
+    // subscribed first so nothing falls between the replay and the live events
+    let last_event_id = headers.get("last-event-id").and_then(|value| value.to_str().ok());
+    let replayed = match (sse_replay_sql, last_event_id) {
+        (Some(sql), Some(last_event_id)) => replay_events(&read_pool, &biscuit, &anon_role, &sql, &channel, last_event_id).await?,
+        _ => vec![],
+    };
+
+    let render = Arc::new(SseRender { read_pool, tls, biscuit, anon_role, query, channel: channel.clone(), fetch_sql: sse_fetch_sql, max_plan_cost, max_plan_rows });
+    let replayed: Vec<_> = futures::stream::iter(&replayed).then(|payload| async { render.event(payload, &OnceCell::new()).await }).collect().await;
+    let replayed_ids: Arc<HashSet<String>> = Arc::new(replayed.iter().filter_map(|(id, _)| id.clone()).collect());
+
+    let live = tokio_stream::wrappers::BroadcastStream::new(rx)
+        .filter_map(move |b| {
+            // unlistens once the client is gone
+            let _subscription = &subscription;
+            let channel = channel.clone();
+            let replayed_ids = replayed_ids.clone();
+            let render = render.clone();
+            async move {
+                match b {
+                    Ok(Broadcast::Notification { channel: c, payload, fetched }) if c == channel => match render.event(&payload, &fetched).await {
+                        (Some(id), _) if replayed_ids.contains(&id) => None,
+                        (_, event) => Some(event),
+                    },
+                    Ok(Broadcast::Reconnected) => Some(Event::default().event("reconnected").data("")),
+                    // too slow to keep up, it's up to the client to resync
+                    Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                        metrics::counter!("httpg_sse_lagged_total").increment(missed);
+                        Some(Event::default().event("lagged").data(missed.to_string()))
+                    },
+                    _ => None,
+                }
+            }
+        })
+        .map(Ok::<_, std::convert::Infallible>)
+    ;
+
+    Ok(Sse::new(futures::stream::iter(replayed.into_iter().map(|(_, event)| Ok(event))).chain(live))
+        .keep_alive(KeepAlive::new().interval(std::time::Duration::from_secs(sse_keep_alive.max(1))))
+    )
+}
+
+async fn live_rows(
+    read_pool: &ReadPool,
+    tls: &MakeRustlsConnect,
+    biscuit: &Option<extract::biscuit::Biscuit>,
+    config: &HttpgConfig,
+    query: &extract::query::Query,
+    sql: &str,
+) -> Result<Vec<serde_json::Value>, HttpgError> {
+    let mut conn = read_pool.get().await?;
+    let tx = conn.build_transaction()
+        .read_only(true)
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .start().await?
+    ;
+    let mut guard = QueryGuard {
+        cancel_token: tx.cancel_token(),
+        tls: tls.clone(),
+        finished: false,
+    };
+    let sql_params: Vec<(_, Type)> = query.params.iter().map(|param| {
+        (param.tosql_sync(), param.to_owned().into())
+    }).collect();
+    let rows = guard.check(async {
+        tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(query)?, Type::TEXT)]).await?;
+        tx.batch_execute(&pre(biscuit, &config.anon_role)?).await?;
+        statement_timeout(&tx, query.statement_timeout, config.max_statement_timeout).await?;
+        guard_plan(&tx, sql, &sql_params, config.max_plan_cost, config.max_plan_rows).await?;
+        let live_sql = format!("select coalesce(jsonb_agg(live), '[]') from ({}) live", sql.trim_end().trim_end_matches(';'));
+        Ok::<_, HttpgError>(tx.query_typed(&live_sql, &sql_params).await?)
+    }).await?;
+    guard.finished = true;
+
+    match rows.first().map(|row| row.try_get(0)).transpose()? {
+        Some(serde_json::Value::Array(rows)) => Ok(rows),
+        _ => Ok(vec![]),
+    }
+}
+
+struct LiveQuery {
+    rx: tokio::sync::broadcast::Receiver<Broadcast>,
+    previous: Vec<serde_json::Value>,
+    // unlistens once the client is gone
+    _subscriptions: Vec<postgres::listen::Subscription>,
+    read_pool: Arc<ReadPool>,
+    tls: MakeRustlsConnect,
+    biscuit: Option<extract::biscuit::Biscuit>,
+    config: HttpgConfig,
+    query: extract::query::Query,
+    sql: String,
+    watch: Vec<String>,
+    key: Option<String>,
+}
+
+impl LiveQuery {
+    async fn next(mut self) -> Option<(Event, Self)> {
+        loop {
+            match self.rx.recv().await {
+                Ok(Broadcast::Notification { channel, .. }) if self.watch.contains(&channel) => {},
+                // notifications may have been missed
+                Ok(Broadcast::Reconnected) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {},
+                Ok(_) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+            // a burst of notifications is one re-run
+            while self.rx.try_recv().is_ok() {}
+
+            let rows = match live_rows(&self.read_pool, &self.tls, &self.biscuit, &self.config, &self.query, &self.sql).await {
+                Ok(rows) => rows,
+                Err(e) => return Some((Event::default().event("error").data(e.to_string()), self)),
+            };
+            let event = match &self.key {
+                Some(key) => response::sse::diff(key, &self.previous, &rows).map(|diff| Event::default().event("diff").data(diff.to_string())),
+                None if rows == self.previous => None,
+                None => serde_json::to_string(&rows).ok().map(|data| Event::default().event("result").data(data)),
+            };
+            self.previous = rows;
+            if let Some(event) = event {
+                return Some((event, self));
+            }
+        }
+    }
+}
+
+#[debug_handler]
+async fn sse_live(
+    State(AppState {listener, read_pool, tls, config, ..}): State<AppState>,
+    biscuit: Option<extract::biscuit::Biscuit>,
+    query: extract::query::Query,
 ) -> Result<impl IntoResponse, HttpgError> {
+    let param = |name: &str| query.qs.get(name).or(query.body.get(name)).cloned();
+    let watch: Vec<String> = match param("watch") {
+        Some(serde_json::Value::String(watch)) => watch.split(',').map(str::trim).filter(|c| !c.is_empty()).map(str::to_owned).collect(),
+        Some(serde_json::Value::Array(watch)) => watch.iter().filter_map(serde_json::Value::as_str).map(str::to_owned).collect(),
+        _ => vec![],
+    };
+    if watch.is_empty() {
+        return Err(HttpgError::InvalidLive { reason: "watch should name the channels to re-run on".into() });
+    }
+    let key = param("key").as_ref().and_then(serde_json::Value::as_str).map(str::to_owned);
+    let Some(sql) = query.sql.clone() else {
+        return Err(HttpgError::InvalidLive { reason: "sql is required".into() });
+    };
+
+    let mut subscriptions = vec![];
+    for channel in &watch {
+        if let Some(sql) = &config.sse_auth_sql {
+            authorize_channel(&read_pool, &biscuit, &config.anon_role, sql, channel).await?;
+        }
+        subscriptions.push(listener.subscribe(channel).await?);
+    }
+    // subscribed first so nothing falls between the first run and the notifications
+    let rx = listener.tx.subscribe();
+    let result = live_rows(&read_pool, &tls, &biscuit, &config, &query, &sql).await?;
+    let first = Event::default().event("result").data(serde_json::to_string(&result)?);
+
+    let keep_alive = std::time::Duration::from_secs(config.sse_keep_alive.max(1));
+    let live = futures::stream::unfold(LiveQuery {
+        rx, previous: result, _subscriptions: subscriptions, read_pool, tls, biscuit, config, query, sql, watch, key,
+    }, LiveQuery::next);
+
+    Ok(Sse::new(futures::stream::once(async { first }).chain(live).map(Ok::<_, std::convert::Infallible>))
+        .keep_alive(KeepAlive::new().interval(keep_alive))
+    )
+}
+
+/// Changes are sent as they come out of the slot, so tables with row level security are refused:
+/// their subscribers would get rows they can't see.
+const CHANGES_AUTH_SQL: &str = "select has_table_privilege($1, 'select') and not relrowsecurity from pg_class where oid = $1::regclass";
 
-    client.execute(&format!("listen {channel}"), &[]).await?;
+#[debug_handler]
+async fn sse_changes(
+    State(AppState {listener, read_pool, config: HttpgConfig {anon_role, sse_keep_alive, ..}, ..}): State<AppState>,
+    biscuit: Option<extract::biscuit::Biscuit>,
+    Path(paths): Path<HashMap<String, String>>,
+    axum::extract::Query(key): axum::extract::Query<BTreeMap<String, String>>,
+) -> Result<impl IntoResponse, HttpgError> {
+    let filter = Filter::new(paths.get("table").map_or("", String::as_str), key);
+    authorize_channel(&read_pool, &biscuit, &anon_role, CHANGES_AUTH_SQL, &filter.relation()).await?;
+
+    let changes = tokio_stream::wrappers::BroadcastStream::new(listener.changes.subscribe())
+        .filter_map(move |b| futures::future::ready(match b {
+            Ok(change) if filter.matches(&change) => Some(match serde_json::to_string(&*change) {
+                Ok(data) => Event::default().event(change.action.as_str()).data(data),
+                Err(e) => Event::default().event("error").data(e.to_string()),
+            }),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                metrics::counter!("httpg_sse_lagged_total").increment(missed);
+                Some(Event::default().event("lagged").data(missed.to_string()))
+            },
+            _ => None,
+        }))
+        .map(Ok::<_, std::convert::Infallible>)
+    ;
+
+    Ok(Sse::new(changes)
+        .keep_alive(KeepAlive::new().interval(std::time::Duration::from_secs(sse_keep_alive.max(1))))
+    )
+}
 
-    Ok(Sse::new(
-        tokio_stream::wrappers::BroadcastStream::new(tx.subscribe())
-            .try_filter_map(move |n| {
-                let channel = channel.clone();
-                async move {
-                    match n.channel() {
-                        c if c == channel => Ok(Some(Event::default().data(n.payload()))),
-                        _ => Ok(None)
+#[debug_handler]
+async fn ws(
+    State(state): State<AppState>,
+    biscuit: Option<extract::biscuit::Biscuit>,
+    headers: http::HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> impl IntoResponse {
+    upgrade.on_upgrade(move |socket| async move {
+        if let Err(e) = ws_session(state, biscuit, headers, socket).await {
+            tracing::warn!("websocket closed: {e}");
+        }
+    })
+}
+
+async fn ws_session(
+    state: AppState,
+    biscuit: Option<extract::biscuit::Biscuit>,
+    headers: http::HeaderMap,
+    mut socket: WebSocket,
+) -> Result<(), HttpgError> {
+    let mut rx = state.listener.tx.subscribe();
+    let mut subscriptions = HashMap::new();
+    let mut tables = HashMap::new();
+    // only received from while subscribed to some table
+    let mut changes: Option<tokio::sync::broadcast::Receiver<Arc<changes::Change>>> = None;
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(ws::Message::Text(text))) => text,
+                    Some(Ok(ws::Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                };
+                let message: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(&text) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        socket.send(ws::Message::Text(json!({"error": e.to_string()}).to_string().into())).await?;
+                        continue;
+                    },
+                };
+                let id = message.get("id").cloned();
+
+                if let Some(channel) = message.get("listen").and_then(serde_json::Value::as_str) {
+                    let subscribed = async {
+                        if let Some(sql) = &state.config.sse_auth_sql {
+                            authorize_channel(&state.read_pool, &biscuit, &state.config.anon_role, sql, channel).await?;
+                        }
+                        state.listener.subscribe(channel).await
+                    }.await;
+                    match subscribed {
+                        Ok(subscription) => {
+                            subscriptions.insert(channel.to_owned(), subscription);
+                            json!({"id": id, "listen": channel})
+                        },
+                        Err(e) => json!({"id": id, "error": e.to_string()}),
                     }
+                } else if let Some(channel) = message.get("unlisten").and_then(serde_json::Value::as_str) {
+                    subscriptions.remove(channel);
+                    json!({"id": id, "unlisten": channel})
+                } else if let Some(table) = message.get("subscribe").and_then(serde_json::Value::as_str) {
+                    let key = message.get("key").and_then(serde_json::Value::as_object).into_iter().flatten()
+                        .map(|(name, value)| (name.clone(), match value {
+                            serde_json::Value::String(s) => s.clone(),
+                            v => v.to_string(),
+                        }))
+                        .collect();
+                    let filter = Filter::new(table, key);
+                    match authorize_channel(&state.read_pool, &biscuit, &state.config.anon_role, CHANGES_AUTH_SQL, &filter.relation()).await {
+                        Ok(()) => {
+                            tables.insert(table.to_owned(), filter);
+                            changes.get_or_insert_with(|| state.listener.changes.subscribe());
+                            json!({"id": id, "subscribe": table})
+                        },
+                        Err(e) => json!({"id": id, "error": e.to_string()}),
+                    }
+                } else if let Some(table) = message.get("unsubscribe").and_then(serde_json::Value::as_str) {
+                    tables.remove(table);
+                    if tables.is_empty() {
+                        changes = None;
+                    }
+                    json!({"id": id, "unsubscribe": table})
+                } else {
+                    let mut req = axum::extract::Request::post("/query").body(axum::body::Body::from(text.to_string()))?;
+                    *req.headers_mut() = headers.clone();
+                    req.headers_mut().insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("application/json"));
+
+                    let response = match extract::query::Query::from_request(req, &state).await {
+                        Ok(query) => match message.get("method").and_then(serde_json::Value::as_str) {
+                            Some("get" | "GET") => stream_query(State(state.clone()), biscuit.clone(), None, query).await.into_response(),
+                            _ => post_query(State(state.clone()), biscuit.clone(), None, query).await.into_response(),
+                        },
+                        Err(response) => response,
+                    };
+                    let status = response.status().as_u16();
+                    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+                    json!({"id": id, "status": status, "body": String::from_utf8_lossy(&body)})
                 }
-            })
-    ))
-    // .keep_alive(
-    //     axum::response::sse::KeepAlive::new()
-    //         .interval(tokio::time::Duration::from_secs(1))
-    //         .text("keep-alive-text"),
-    // ))
+            },
+            b = rx.recv() => match b {
+                Ok(Broadcast::Notification { channel, payload, fetched }) if subscriptions.contains_key(&channel) => {
+                    match resolve_payload(&state.read_pool, &state.config.anon_role, state.config.sse_fetch_sql.as_deref(), &channel, &payload, &fetched).await {
+                        Ok(payload) => json!({"channel": channel, "payload": payload}),
+                        Err(e) => json!({"channel": channel, "error": e.to_string()}),
+                    }
+                },
+                Ok(Broadcast::Notification {..}) => continue,
+                Ok(Broadcast::Reconnected) => json!({"event": "reconnected"}),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    metrics::counter!("httpg_sse_lagged_total").increment(missed);
+                    json!({"event": "lagged", "missed": missed})
+                },
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            c = async { match changes.as_mut() {
+                Some(changes) => changes.recv().await,
+                None => std::future::pending().await,
+            } } => match c {
+                Ok(change) if tables.values().any(|f: &Filter| f.matches(&change)) => json!({"change": *change}),
+                Ok(_) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    metrics::counter!("httpg_sse_lagged_total").increment(missed);
+                    json!({"event": "lagged", "missed": missed})
+                },
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        socket.send(ws::Message::Text(reply.to_string().into())).await?;
+    }
+}
+
+// how long a write waits for a new primary, from the first failed attempt
+struct Failover {
+    window: std::time::Duration,
+    started: Option<std::time::Instant>,
+    backoff: std::time::Duration,
+}
+
+impl Failover {
+    fn new(window: u64) -> Self {
+        Self {
+            window: std::time::Duration::from_millis(window),
+            started: None,
+            backoff: std::time::Duration::from_millis(10),
+        }
+    }
+
+    async fn wait(&mut self) -> bool {
+        let started = *self.started.get_or_insert_with(std::time::Instant::now);
+        let left = self.window.saturating_sub(started.elapsed());
+        if left.is_zero() {
+            return false;
+        }
+        tokio::time::sleep(self.backoff.min(left)).await;
+        self.backoff = self.backoff.saturating_mul(2).min(std::time::Duration::from_secs(1));
+        true
+    }
 }
 
 async fn post_query(
-    State(AppState {ref read_pool, ref write_pool, config: HttpgConfig {ref anon_role, ..}, ..}): State<AppState>,
+    State(AppState {ref read_pool, ref write_pool, ref tls, config: HttpgConfig {ref anon_role, tls: ref https, read_your_writes_window, max_statement_timeout, failover_wait, ..}, ..}): State<AppState>,
     biscuit: Option<extract::biscuit::Biscuit>,
     paths: Option<Path<HashMap<String, String>>>,
     query: extract::query::Query,
@@ -579,73 +1326,133 @@ async fn post_query(
     }).collect();
 
     let mut retry: u8 = 0;
+    let mut failover = Failover::new(failover_wait);
     loop {
-        let mut conn = write_pool.get().await?;
-        let tx = conn.build_transaction().isolation_level(IsolationLevel::Serializable).start().await?;
+        let mut conn = match write_pool.get().await {
+            // a busy pool isn't a missing primary
+            Err(e @ (PoolError::Backend(_) | PoolError::Timeout(TimeoutType::Create))) => {
+                tracing::warn!("no primary to write to: {e}");
+                if failover.wait().await {
+                    continue;
+                }
+                return Err(e.into());
+            },
+            conn => conn?,
+        };
+        let tx = match conn.build_transaction().isolation_level(IsolationLevel::Serializable).start().await {
+            Err(e) if e.is_closed() => {
+                tracing::warn!("lost the primary: {e}");
+                if failover.wait().await {
+                    continue;
+                }
+                return Err(e.into());
+            },
+            tx => tx?,
+        };
 
-        let guard = QueryGuard {
+        let mut guard = QueryGuard {
             cancel_token: tx.cancel_token(),
+            tls: tls.clone(),
             finished: false,
         };
-        tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
-        tx.batch_execute(&pre(&biscuit, anon_role)).await?;
+        guard.check(async {
+            tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
+            tx.batch_execute(&pre(&biscuit, anon_role)?).await?;
+            statement_timeout(&tx, query.statement_timeout, max_statement_timeout).await
+        }).await?;
 
-        let result = tx.query_typed(query.sql.as_ref().ok_or(HttpgError::anyhow("no sql passed"))?, &sql_params).await;
+        let sql = query.sql.as_ref().ok_or(HttpgError::anyhow("no sql passed"))?;
+        let result = guard.check(tx.query_typed(sql, &sql_params)).await;
 
         match result {
             Ok(rows) => {
-                let rows = match paths {
-                    Some(paths) => match paths.get("cursor") {
-                        Some(cursor) => {
-                            let rows = tx.query_typed(&format!("fetch all from {cursor}"), sql_params.as_slice()).await?;
-                            tx.execute(&format!("close {cursor}"), &[]).await?;
-                            rows
+                let rows = guard.check(async {
+                    let rows = match paths {
+                        Some(paths) => match paths.get("cursor") {
+                            Some(cursor) => {
+                                let rows = tx.query_typed(&format!("fetch all from {cursor}"), sql_params.as_slice()).await?;
+                                tx.execute(&format!("close {cursor}"), &[]).await?;
+                                rows
+                            },
+                            None => rows,
                         },
                         None => rows,
-                    },
-                    None => rows,
-                };
-                tx.commit().await?;
+                    };
+                    tx.commit().await?;
+                    Ok::<_, tokio_postgres::Error>(rows)
+                }).await?;
 
-                return Ok(response::HttpResult {
+                let mut response = response::HttpResult {
                     query,
                     rows: CancelStream::from_vec(rows, guard),
-                }.into_response());
+                }.into_response();
+                // appended, so that the Set-Cookie headers returned by the sql are kept
+                // the write is committed by now, so a missing cookie only costs read-your-writes
+                if !read_pool.is_empty() {
+                    match conn.query_one("select pg_current_wal_lsn()::text", &[]).await.and_then(|row| row.try_get::<_, String>(0)) {
+                        Ok(lsn) => {
+                            let cookie = Cookie::build(("httpg_lsn", lsn))
+                                .http_only(true)
+                                .path("/")
+                                .secure(https.is_some())
+                                .same_site(cookie::SameSite::Lax)
+                                .max_age(Duration::seconds(read_your_writes_window))
+                                .to_string();
+                            response.headers_mut().append(SET_COOKIE, http::HeaderValue::try_from(cookie)?);
+                        },
+                        Err(e) => tracing::warn!("could not read the wal lsn: {e}"),
+                    }
+                }
+                return Ok(response);
             },
             Err(e) => {
                 let error = &e.as_db_error().map(|e| e.message().to_string()).unwrap_or(e.to_string());
                 tracing::warn!(error);
                 
-                retry = retry.checked_add(1).ok_or(HttpgError::anyhow("Retry overflow"))?;
-                if retry > 5 {
-                    break Err(e);
-                }
                 if matches!(e.code(),
                     Some(&tokio_postgres::error::SqlState::T_R_SERIALIZATION_FAILURE)
                     | Some(&tokio_postgres::error::SqlState::T_R_DEADLOCK_DETECTED))
                 {
+                    retry = retry.checked_add(1).ok_or(HttpgError::anyhow("Retry overflow"))?;
+                    if retry > 5 {
+                        break Err(e);
+                    }
                     tokio::time::sleep(std::time::Duration::from_millis(retry.into())).await;
                     continue;
                 }
+                if e.is_closed() || e.code() == Some(&tokio_postgres::error::SqlState::READ_ONLY_SQL_TRANSACTION) {
+                    // the primary was demoted or went away: forget its connections, new ones look for the read-write host
+                    tracing::warn!("primary lost, reconnecting");
+                    drop(tx);
+                    drop(deadpool_postgres::Object::take(conn));
+                    write_pool.retain(|_, _| false);
+                    if failover.wait().await {
+                        continue;
+                    }
+                    break Err(e);
+                }
 
                 match &query.on_error {
                     Some(on_error) => {
                         let mut conn = read_pool.get().await?;
                         let tx = conn.build_transaction().read_only(true).isolation_level(IsolationLevel::RepeatableRead).start().await?;
 
-                        let guard = QueryGuard {
+                        let mut guard = QueryGuard {
                             cancel_token: tx.cancel_token(),
+                            tls: tls.clone(),
                             finished: false,
                         };
-                        tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
-                        tx.batch_execute(&pre(&biscuit, anon_role)).await?;
+                        let rows = guard.check(async {
+                            tx.query_typed_raw("select set_config('httpg.query', $1, true)", [(serde_json::to_string(&query)?, Type::TEXT)]).await?;
+                            tx.batch_execute(&pre(&biscuit, anon_role)?).await?;
 
-                        tx.query_typed_raw(
-                            "select set_config('httpg.errors', $1, true)",
-                            vec![(serde_json::to_string(&json!({"error": &error}))?, Type::TEXT)]
-                        ).await?;
+                            tx.query_typed_raw(
+                                "select set_config('httpg.errors', $1, true)",
+                                vec![(serde_json::to_string(&json!({"error": &error}))?, Type::TEXT)]
+                            ).await?;
 
-                        let rows = tx.query_typed_raw(on_error.as_ref(), sql_params).await?;
+                            Ok::<_, HttpgError>(tx.query_typed_raw(on_error.as_ref(), sql_params).await?)
+                        }).await?;
 
                         let mut query = query.clone();
                         query.redirect = None;
@@ -669,3 +1476,35 @@ async fn post_query(
         }
     }?
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use conf::Conf;
+
+    use crate::{HttpgConfig, statement_timeout};
+
+    #[tokio::test]
+    async fn test_statement_timeout_cap() {
+        let httpg_config = HttpgConfig::parse();
+        let tls = httpg_config.pg.tls().unwrap();
+        let write_pool = httpg_config.pg.write_pool(tls).unwrap();
+        let mut conn = write_pool.get().await.unwrap();
+        let tx = conn.transaction().await.unwrap();
+        tx.batch_execute("set local statement_timeout = '5s'").await.unwrap();
+
+        let current = async || tx.query_one("select setting::int8 from pg_settings where name = 'statement_timeout'", &[]).await.unwrap().get::<_, i64>(0);
+
+        assert!(statement_timeout(&tx, Some(0), Some(60_000)).await.is_err());
+        assert_eq!(current().await, 5000);
+
+        statement_timeout(&tx, Some(600_000), None).await.unwrap();
+        assert_eq!(current().await, 5000);
+
+        statement_timeout(&tx, Some(600_000), Some(60_000)).await.unwrap();
+        assert_eq!(current().await, 60_000);
+
+        statement_timeout(&tx, Some(1000), None).await.unwrap();
+        assert_eq!(current().await, 1000);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlparser::ast::Ident;
+use tokio::sync::broadcast::Sender;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::{HttpgError, postgres::PostgresConfig};
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Insert,
+    Update,
+    Delete,
+    Truncate,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Insert => "insert",
+            Self::Update => "update",
+            Self::Delete => "delete",
+            Self::Truncate => "truncate",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Change {
+    pub action: Action,
+    pub schema: String,
+    pub table: String,
+    pub key: Map<String, Value>,
+    pub new: Option<Map<String, Value>>,
+    pub old: Option<Map<String, Value>>,
+}
+
+#[derive(Deserialize)]
+struct Column {
+    name: String,
+    #[serde(default)]
+    value: Value,
+}
+
+#[derive(Deserialize)]
+struct Wal2Json {
+    action: String,
+    #[serde(default)]
+    schema: String,
+    #[serde(default)]
+    table: String,
+    columns: Option<Vec<Column>>,
+    identity: Option<Vec<Column>>,
+    #[serde(default)]
+    pk: Vec<Column>,
+}
+
+impl Change {
+    pub fn parse(data: &str) -> Result<Option<Self>, HttpgError> {
+        let w: Wal2Json = serde_json::from_str(data)?;
+        let action = match w.action.as_str() {
+            "I" => Action::Insert,
+            "U" => Action::Update,
+            "D" => Action::Delete,
+            "T" => Action::Truncate,
+            _ => return Ok(None),
+        };
+        let row = |columns: Option<Vec<Column>>| columns.map(|columns| columns.into_iter()
+            .map(|c| (c.name, c.value))
+            .collect::<Map<_, _>>()
+        );
+        let new = row(w.columns);
+        let old = row(w.identity);
+        let key = w.pk.iter()
+            .filter_map(|pk| old.as_ref().or(new.as_ref())?.get(&pk.name).map(|value| (pk.name.clone(), value.clone())))
+            .collect();
+
+        Ok(Some(Self { action, schema: w.schema, table: w.table, key, new, old }))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Filter {
+    pub schema: String,
+    pub table: String,
+    pub key: BTreeMap<String, String>,
+}
+
+impl Filter {
+    pub fn new(table: &str, key: BTreeMap<String, String>) -> Self {
+        let (schema, table) = table.split_once('.').unwrap_or(("public", table));
+        Self { schema: schema.to_owned(), table: table.to_owned(), key }
+    }
+
+    pub fn relation(&self) -> String {
+        format!("{}.{}", Ident::with_quote('"', &self.schema), Ident::with_quote('"', &self.table))
+    }
+
+    /// Key values are compared as text, since they come from a url; a truncate matches any key.
+    pub fn matches(&self, change: &Change) -> bool {
+        change.schema == self.schema
+            && change.table == self.table
+            && (change.action == Action::Truncate || self.key.iter().all(|(name, value)| match change.key.get(name) {
+                Some(Value::String(s)) => s == value,
+                Some(v) => serde_json::from_str::<Value>(value).is_ok_and(|parsed| parsed == *v),
+                None => false,
+            }))
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Changes are consumed whether or not anyone is subscribed.
+pub async fn consume(pg: PostgresConfig, tls: MakeRustlsConnect, slot: String, tx: Sender<Arc<Change>>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let mut streamed = false;
+        match stream(&pg, tls.clone(), &slot, &tx, &mut streamed).await {
+            Ok(()) => tracing::warn!(slot, "replication stream ended"),
+            Err(e) => tracing::warn!(slot, "replication stream failed: {e}"),
+        }
+        if streamed {
+            backoff = MIN_BACKOFF;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+    }
+}
+
+async fn stream(pg: &PostgresConfig, tls: MakeRustlsConnect, slot: &str, tx: &Sender<Arc<Change>>, streamed: &mut bool) -> Result<(), HttpgError> {
+    let mut replication = pg.replication(tls).await?;
+    replication.create_slot(slot, "wal2json").await?;
+    replication.start(slot, &[("format-version", "2"), ("include-pk", "1"), ("include-transaction", "0")]).await?;
+    *streamed = true;
+
+    while let Some(data) = replication.next(STATUS_INTERVAL).await? {
+        match Change::parse(&data) {
+            Ok(Some(change)) => {
+                metrics::counter!("httpg_changes_total", "table" => format!("{}.{}", change.schema, change.table)).increment(1);
+                // nobody subscribed is fine
+                let _ = tx.send(Arc::new(change));
+            },
+            Ok(None) => {},
+            Err(e) => tracing::warn!(slot, data, "could not decode change: {e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use super::{Action, Change, Filter};
+
+    #[test]
+    fn test_parse_and_filter() {
+        let insert = Change::parse(r#"{"action":"I","schema":"blog","table":"post","columns":[{"name":"id","type":"integer","value":1},{"name":"title","type":"text","value":"hi"}],"pk":[{"name":"id","type":"integer"}]}"#).unwrap().unwrap();
+        assert_eq!(insert.action, Action::Insert);
+        assert_eq!(insert.key, json!({"id": 1}).as_object().unwrap().clone());
+        assert_eq!(insert.old, None);
+
+        let delete = Change::parse(r#"{"action":"D","schema":"blog","table":"post","identity":[{"name":"id","type":"integer","value":2}],"pk":[{"name":"id","type":"integer"}]}"#).unwrap().unwrap();
+        assert_eq!(delete.key, json!({"id": 2}).as_object().unwrap().clone());
+        assert_eq!(delete.new, None);
+
+        assert_eq!(Change::parse(r#"{"action":"B"}"#).unwrap(), None);
+
+        let any = Filter::new("blog.post", BTreeMap::new());
+        let one = Filter::new("blog.post", BTreeMap::from([("id".into(), "1".into())]));
+        assert!(any.matches(&insert) && any.matches(&delete));
+        assert!(one.matches(&insert) && !one.matches(&delete));
+        assert!(!Filter::new("post", BTreeMap::new()).matches(&insert));
+        assert_eq!(Filter::new("post", BTreeMap::new()).relation(), r#""public"."post""#);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use sqlparser::ast::Ident;
+use tokio::sync::{Mutex, OnceCell, RwLock, broadcast::Sender};
+use tokio_postgres::{AsyncMessage, Client};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::{HttpgError, postgres::{PostgresConfig, changes::Change}};
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub enum Broadcast {
+    Notification {
+        channel: String,
+        payload: String,
+        fetched: Arc<OnceCell<String>>,
+    },
+    Reconnected,
+}
+
+pub struct Listener {
+    client: RwLock<Option<Client>>,
+    channels: Mutex<BTreeMap<String, usize>>,
+    pub tx: Sender<Broadcast>,
+    // apart, so that row changes don't crowd notifications out
+    pub changes: Sender<Arc<Change>>,
+}
+
+pub struct Subscription {
+    listener: Arc<Listener>,
+    channel: String,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let listener = self.listener.clone();
+        let channel = std::mem::take(&mut self.channel);
+        tokio::spawn(async move {
+            if let Err(e) = listener.unsubscribe(&channel).await {
+                tracing::warn!(channel, "could not unlisten: {e}");
+            }
+        });
+    }
+}
+
+impl Listener {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            client: RwLock::new(None),
+            channels: Mutex::new(BTreeMap::new()),
+            tx: tokio::sync::broadcast::channel(capacity).0,
+            changes: tokio::sync::broadcast::channel(capacity).0,
+        }
+    }
+
+    pub async fn subscribe(self: &Arc<Self>, channel: &str) -> Result<Subscription, HttpgError> {
+        let mut channels = self.channels.lock().await;
+        if !channels.contains_key(channel) && let Some(client) = self.client.read().await.as_ref() {
+            client.batch_execute(&listen(channel)).await?;
+        }
+        let count = channels.entry(channel.to_owned()).or_default();
+        *count = count.saturating_add(1);
+        // channels come from urls, so they aren't labels
+        metrics::gauge!("httpg_sse_subscribers").increment(1);
+        metrics::gauge!("httpg_sse_channels").set(u32::try_from(channels.len()).unwrap_or(u32::MAX));
+
+        Ok(Subscription { listener: self.clone(), channel: channel.to_owned() })
+    }
+
+    pub async fn channels(&self) -> BTreeMap<String, usize> {
+        self.channels.lock().await.clone()
+    }
+
+    async fn unsubscribe(&self, channel: &str) -> Result<(), HttpgError> {
+        let mut channels = self.channels.lock().await;
+        metrics::gauge!("httpg_sse_subscribers").decrement(1);
+        let Some(count) = channels.get_mut(channel) else {
+            return Ok(());
+        };
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            channels.remove(channel);
+            metrics::gauge!("httpg_sse_channels").set(u32::try_from(channels.len()).unwrap_or(u32::MAX));
+            if let Some(client) = self.client.read().await.as_ref() {
+                client.batch_execute(&format!("unlisten {}", Ident::with_quote('"', channel))).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn supervise(self: Arc<Self>, pg: PostgresConfig, tls: MakeRustlsConnect) {
+        let mut backoff = MIN_BACKOFF;
+        let mut reconnect = false;
+        loop {
+            match self.run(&pg, tls.clone(), reconnect).await {
+                Ok(()) => tracing::warn!("listen connection closed"),
+                Err(e) => tracing::warn!("listen connection failed: {e}"),
+            }
+            if self.client.write().await.take().is_some() {
+                backoff = MIN_BACKOFF;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+            reconnect = true;
+        }
+    }
+
+    async fn run(&self, pg: &PostgresConfig, tls: MakeRustlsConnect, reconnect: bool) -> Result<(), HttpgError> {
+        let (client, mut conn) = pg.connect(tls).await?;
+
+        let tx = self.tx.clone();
+        let pump = tokio::spawn(async move {
+            let mut stream = futures::stream::poll_fn(move |cx| conn.poll_message(cx));
+            while let Some(m) = stream.next().await {
+                match m? {
+                    AsyncMessage::Notice(n) => {
+                        tracing::info!("{n:#?}");
+                    },
+                    AsyncMessage::Notification(n) => {
+                        // nobody subscribed is fine
+                        let _ = tx.send(Broadcast::Notification {
+                            channel: n.channel().to_owned(),
+                            payload: n.payload().to_owned(),
+                            fetched: Arc::new(OnceCell::new()),
+                        });
+                    },
+                    _ => {},
+                }
+            }
+            Ok::<_, HttpgError>(())
+        });
+
+        let channels = self.channels.lock().await;
+        for channel in channels.keys() {
+            client.batch_execute(&listen(channel)).await?;
+        }
+        *self.client.write().await = Some(client);
+        drop(channels);
+        if reconnect {
+            tracing::info!("listen connection re-established");
+            let _ = self.tx.send(Broadcast::Reconnected);
+        }
+
+        pump.await.map_err(|e| HttpgError::anyhow(e.to_string()))?
+    }
+}
+
+/// Channels are quoted identifiers, so any name can be listened to, case included.
+fn listen(channel: &str) -> String {
+    format!("listen {}", Ident::with_quote('"', channel))
+}
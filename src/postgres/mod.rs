@@ -1,57 +1,108 @@
 
+pub mod changes;
+pub mod listen;
+pub mod replica;
+pub mod replication;
+
 use std::{fs, sync::Arc};
 
 use conf::Conf;
-use deadpool_postgres::{Pool, Runtime};
-use rustls::{client::danger::{HandshakeSignatureValid, ServerCertVerified}, pki_types::{CertificateDer, ServerName, UnixTime}};
+use deadpool_postgres::{ManagerConfig, Pool, PoolConfig, RecyclingMethod, Runtime};
+use rustls::{CertificateError, client::{WebPkiServerVerifier, danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}}, pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject}, RootCertStore};
 use tokio_postgres::{CancelToken, Client, Connection, Socket, tls::TlsStream};
 use tokio_postgres_rustls::MakeRustlsConnect;
 
-use crate::{HttpgError};
+use crate::{HttpgError, postgres::replica::{Balance, ReadPool}};
 
 #[derive(Clone, Debug, Conf)]
 pub struct PostgresConfig {
+    #[conf(repeat, long, env)]
+    read_host: Vec<String>,
     #[conf(long, env)]
-    read_host: String,
-    #[conf(long, env)]
-    write_host: String,
+    read_balance: Option<String>,
+    #[conf(long, env, default_value="5")]
+    health_check_interval: std::num::NonZeroU64,
+    #[conf(repeat, long, env)]
+    write_host: Vec<String>,
     #[conf(long, env)]
     user: String,
     #[conf(long="password-file", env="PASSWORD_FILE", value_parser = |file: &str| -> Result<_, HttpgError> { Ok(fs::read_to_string(file)?) })]
-    password: String,
+    password: Option<String>,
     #[conf(long, env)]
     dbname: String,
     #[conf(long, env)]
     channel_binding: Option<String>,
     #[conf(long, env)]
     ssl_mode: Option<String>,
+    #[conf(long, env)]
+    ssl_root_cert: Option<String>,
+    #[conf(long, env)]
+    ssl_cert: Option<String>,
+    #[conf(long, env)]
+    ssl_key: Option<String>,
     #[conf(long, env, default_value="httpg")]
     application_name: String,
+    #[conf(long, env)]
+    pool_max_size: Option<usize>,
+    #[conf(long, env, default_value="1000")]
+    pool_wait_timeout: u64,
+    #[conf(long, env)]
+    pool_create_timeout: Option<u64>,
+    #[conf(long, env)]
+    pool_recycle_timeout: Option<u64>,
+    #[conf(long, env)]
+    recycling_method: Option<String>,
+    // discard all would also drop the statements tokio-postgres prepared to look up types
+    #[conf(long, env, default_value="close all; set session authorization default; reset all; unlisten *; select pg_advisory_unlock_all(); discard temp; discard sequences")]
+    session_reset: String,
+    #[conf(long, env, default_value="1000")]
+    connect_timeout: u64,
 }
 
 impl PostgresConfig {
-    pub fn read_pool(&self) -> Result<Pool, HttpgError> {
-        let mut cfg = deadpool_postgres::Config::new();
+    pub fn read_pool(&self, tls: MakeRustlsConnect, primary: Pool) -> Result<ReadPool, HttpgError> {
+        let replicas = self.read_host.iter().map(|host| {
+            let mut cfg = deadpool_postgres::Config::new();
 
-        cfg.host = Some(self.read_host.clone());
-        self.rest(&mut cfg)
+            cfg.host = Some(host.clone());
+            let pool = self.rest(&mut cfg, tls.clone())?;
+            let config = cfg.get_pg_config().map_err(|e| HttpgError::anyhow(e.to_string()))?;
+            Ok((host.clone(), pool, config))
+        }).collect::<Result<_, HttpgError>>()?;
+
+        Ok(ReadPool::new(replicas, primary, match self.read_balance.as_deref() {
+            Some("least-connections") => Balance::LeastConnections,
+            _ => Balance::RoundRobin,
+        }, tls))
     }
 
-    pub fn write_pool(&self) -> Result<Pool, HttpgError> {
+    pub fn health_check_interval(&self) -> core::time::Duration {
+        core::time::Duration::from_secs(self.health_check_interval.get())
+    }
+
+    pub fn write_pool(&self, tls: MakeRustlsConnect) -> Result<Pool, HttpgError> {
         let mut cfg = deadpool_postgres::Config::new();
 
-        cfg.host = Some(self.write_host.clone());
-        self.rest(&mut cfg)
+        cfg.hosts = Some(self.write_host.clone());
+        cfg.target_session_attrs = Some(deadpool_postgres::TargetSessionAttrs::ReadWrite);
+        self.rest(&mut cfg, tls)
     }
 
-    pub async fn connect(&self) -> Result<(Client, Connection<Socket, impl TlsStream + use<>>), HttpgError> {
-        let cfg = tokio_postgres::Config::new()
+    pub async fn connect(&self, tls: MakeRustlsConnect) -> Result<(Client, Connection<Socket, impl TlsStream + use<>>), HttpgError> {
+        let mut cfg = tokio_postgres::Config::new();
+        for host in &self.write_host {
+            cfg.host(host);
+        }
+        if let Some(password) = &self.password {
+            cfg.password(password.clone());
+        }
+        let cfg = cfg
+            .target_session_attrs(tokio_postgres::config::TargetSessionAttrs::ReadWrite)
             .user(self.user.clone())
-            .password(self.password.clone())
             .dbname(self.dbname.clone())
-            .host(self.write_host.clone())
             .ssl_mode(match self.ssl_mode.as_deref() {
-                Some("require") => tokio_postgres::config::SslMode::Require,
+                Some("require" | "verify-ca" | "verify-full") => tokio_postgres::config::SslMode::Require,
+                Some("disable") => tokio_postgres::config::SslMode::Disable,
                 _ => tokio_postgres::config::SslMode::Prefer,
             })
             .channel_binding(match self.channel_binding.as_deref() {
@@ -59,25 +110,20 @@ impl PostgresConfig {
                 _ => tokio_postgres::config::ChannelBinding::Prefer,
             })
             .application_name(self.application_name.to_owned())
-            // .options("-c statement_timeout=600ms")
+            .connect_timeout(core::time::Duration::from_millis(self.connect_timeout))
             .to_owned()
         ;
 
-        let tls_config = rustls::ClientConfig::builder()
-            .dangerous()
-            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
-            .with_no_client_auth()
-        ;
-
-        cfg.connect(MakeRustlsConnect::new(tls_config)).await.map_err(Into::into)
+        cfg.connect(tls).await.map_err(Into::into)
     }
 
-    fn rest(&self, cfg: &mut deadpool_postgres::Config) -> Result<Pool, HttpgError> {
+    fn rest(&self, cfg: &mut deadpool_postgres::Config, tls: MakeRustlsConnect) -> Result<Pool, HttpgError> {
         cfg.user = Some(self.user.clone());
-        cfg.password = Some(self.password.clone());
+        cfg.password = self.password.clone();
         cfg.dbname = Some(self.dbname.clone());
         cfg.ssl_mode = Some(match self.ssl_mode.as_deref() {
-            Some("require") => deadpool_postgres::SslMode::Require,
+            Some("require" | "verify-ca" | "verify-full") => deadpool_postgres::SslMode::Require,
+            Some("disable") => deadpool_postgres::SslMode::Disable,
             _ => deadpool_postgres::SslMode::Prefer,
         });
         cfg.channel_binding = Some(match self.channel_binding.as_deref() {
@@ -86,42 +132,150 @@ impl PostgresConfig {
         });
         cfg.application_name = Some(self.application_name.to_owned());
 
-        // cfg.options = Some("-c statement_timeout=600ms".into());
+        cfg.connect_timeout = Some(core::time::Duration::from_millis(self.connect_timeout));
+
+        let mut pool = self.pool_max_size.map(PoolConfig::new).unwrap_or_default();
+        pool.timeouts.wait = Some(core::time::Duration::from_millis(self.pool_wait_timeout));
+        pool.timeouts.create = self.pool_create_timeout.map(core::time::Duration::from_millis);
+        pool.timeouts.recycle = self.pool_recycle_timeout.map(core::time::Duration::from_millis);
+        cfg.pool = Some(pool);
+        let method = match self.recycling_method.as_deref() {
+            None | Some("fast") => RecyclingMethod::Fast,
+            Some("verified") => RecyclingMethod::Verified,
+            Some("clean") => RecyclingMethod::Clean,
+            Some(method) => return Err(HttpgError::anyhow(format!("unknown recycling method {method}"))),
+        };
+        cfg.manager = Some(ManagerConfig {
+            // the session_reset runs after what the recycling method runs, if anything
+            recycling_method: match (method.query(), self.session_reset.as_str()) {
+                (_, "") => method,
+                (None | Some(""), reset) => RecyclingMethod::Custom(reset.to_owned()),
+                (Some(sql), reset) => RecyclingMethod::Custom(format!("{sql} {reset}")),
+            },
+        });
+
+        cfg.create_pool(Some(Runtime::Tokio1), tls).map_err(Into::into)
+    }
 
-        let tls_config = rustls::ClientConfig::builder()
+    /// Like libpq: `verify-full` checks the chain and the host name against `ssl_root_cert`,
+    /// `verify-ca` (or `require` with a root cert) only the chain, anything else nothing.
+    pub fn tls(&self) -> Result<MakeRustlsConnect, HttpgError> {
+        let roots = |file: &String| -> Result<_, HttpgError> {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(file)? {
+                roots.add(cert?)?;
+            }
+            Ok(Arc::new(roots))
+        };
+
+        let verifier: Arc<dyn ServerCertVerifier> = match (self.ssl_mode.as_deref(), &self.ssl_root_cert) {
+            (Some("verify-full"), Some(root)) => WebPkiServerVerifier::builder(roots(root)?).build()?,
+            (Some("verify-ca" | "require"), Some(root)) => Arc::new(VerifyCa(WebPkiServerVerifier::builder(roots(root)?).build()?)),
+            (Some("verify-ca" | "verify-full"), None) => return Err(HttpgError::anyhow("ssl_mode verify-ca and verify-full need ssl_root_cert")),
+            _ => Arc::new(NoCertificateVerification {}),
+        };
+
+        let builder = rustls::ClientConfig::builder()
             .dangerous()
-            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
-            .with_no_client_auth()
+            .with_custom_certificate_verifier(verifier)
         ;
-        let tls = MakeRustlsConnect::new(tls_config);
+        let tls_config = match (&self.ssl_cert, &self.ssl_key) {
+            (Some(cert), Some(key)) => builder.with_client_auth_cert(
+                CertificateDer::pem_file_iter(cert)?.collect::<Result<_, _>>()?,
+                PrivateKeyDer::from_pem_file(key)?,
+            )?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(HttpgError::anyhow("ssl_cert and ssl_key go together")),
+        };
 
-        cfg.connect_timeout = Some(core::time::Duration::from_secs(1));
-
-        cfg.create_pool(Some(Runtime::Tokio1), tls).map_err(Into::into)
+        Ok(MakeRustlsConnect::new(tls_config))
     }
 }
 
 pub struct QueryGuard {
     pub cancel_token: CancelToken,
+    pub tls: MakeRustlsConnect,
     pub finished: bool,
 }
 
+impl QueryGuard {
+    // a failed query isn't running anymore, so there's nothing to cancel
+    pub async fn check<T, E>(&mut self, result: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+        let result = result.await;
+        self.finished |= result.is_err();
+        result
+    }
+}
+
 impl Drop for QueryGuard {
     fn drop(&mut self) {
         if self.finished {
             return;
         }
         let cancel_token = self.cancel_token.clone();
+        let tls = self.tls.clone();
         tokio::spawn(async move {
-            let _ = cancel_token.cancel_query(tokio_postgres::NoTls).await;
+            match cancel_token.cancel_query(tls).await {
+                Ok(()) => {
+                    tracing::debug!("cancelled unfinished query");
+                    metrics::counter!("httpg_query_cancellations_total", "result" => "ok").increment(1);
+                },
+                Err(e) => {
+                    tracing::warn!("could not cancel unfinished query: {e}");
+                    metrics::counter!("httpg_query_cancellations_total", "result" => "error").increment(1);
+                },
+            }
         });
     }
 }
 
+#[derive(Debug)]
+pub struct VerifyCa(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for VerifyCa {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        match self.0.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now) {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName | CertificateError::NotValidForNameContext {..})) => {
+                Ok(ServerCertVerified::assertion())
+            },
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
 #[derive(Debug)]
 pub struct NoCertificateVerification {}
 
-impl rustls::client::danger::ServerCertVerifier for NoCertificateVerification {
+impl ServerCertVerifier for NoCertificateVerification {
     fn verify_server_cert(
         &self,
         _end_entity: &CertificateDer<'_>,
@@ -167,3 +321,29 @@ impl rustls::client::danger::ServerCertVerifier for NoCertificateVerification {
     }
 }
 
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use conf::Conf;
+
+    use crate::HttpgConfig;
+
+    #[tokio::test]
+    async fn test_session_reset_keeps_type_lookups() {
+        let mut pg = HttpgConfig::parse().pg;
+        pg.pool_max_size = Some(1);
+        let pool = pg.write_pool(pg.tls().unwrap()).unwrap();
+
+        let conn = pool.get().await.unwrap();
+        conn.batch_execute("drop type if exists httpg_test_mood; create type httpg_test_mood as enum ('ok')").await.unwrap();
+        conn.query("select 'ok'::httpg_test_mood", &[]).await.unwrap();
+        drop(conn);
+
+        // recycled, then looking up a type it hasn't seen yet
+        let conn = pool.get().await.unwrap();
+        let result = conn.query("select array['ok'::httpg_test_mood]", &[]).await;
+        conn.batch_execute("drop type httpg_test_mood").await.unwrap();
+        result.unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::{sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, time::Duration};
+
+use deadpool_postgres::{Object, Pool, PoolError, TimeoutType};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+}
+
+pub struct Replica {
+    host: String,
+    pool: Pool,
+    config: tokio_postgres::Config,
+    healthy: AtomicBool,
+}
+
+impl Replica {
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            match healthy {
+                true => tracing::info!(host = self.host, "re-admitting replica"),
+                false => tracing::warn!(host = self.host, "ejecting replica"),
+            }
+        }
+        metrics::gauge!("httpg_replica_healthy", "host" => self.host.to_owned()).set(if healthy { 1.0 } else { 0.0 });
+    }
+
+    fn load(&self) -> usize {
+        let status = self.pool.status();
+        status.size.saturating_sub(status.available).saturating_add(status.waiting)
+    }
+
+    // on its own connection, so that a saturated pool isn't taken for a dead replica
+    async fn probe(&self, tls: MakeRustlsConnect) -> Result<(), tokio_postgres::Error> {
+        let (client, connection) = self.config.connect(tls).await?;
+        let connection = tokio::spawn(connection);
+        let result = client.simple_query("select 1").await.map(|_| ());
+        drop(client);
+        let _ = connection.await;
+        result
+    }
+}
+
+pub struct ReadPool {
+    replicas: Vec<Replica>,
+    primary: Pool,
+    balance: Balance,
+    next: AtomicUsize,
+    tls: MakeRustlsConnect,
+}
+
+impl ReadPool {
+    pub fn new(replicas: Vec<(String, Pool, tokio_postgres::Config)>, primary: Pool, balance: Balance, tls: MakeRustlsConnect) -> Self {
+        Self {
+            replicas: replicas.into_iter().map(|(host, pool, config)| Replica {
+                host,
+                pool,
+                config,
+                healthy: AtomicBool::new(true),
+            }).collect(),
+            primary,
+            balance,
+            next: AtomicUsize::new(0),
+            tls,
+        }
+    }
+
+    pub async fn get(&self) -> Result<Object, PoolError> {
+        for replica in self.healthy() {
+            match replica.pool.get().await {
+                Ok(conn) => return Ok(conn),
+                // a busy replica is still healthy
+                Err(e @ PoolError::Timeout(TimeoutType::Wait)) => return Err(e),
+                Err(e) => {
+                    tracing::warn!(host = replica.host, "{e}");
+                    replica.set_healthy(false);
+                },
+            }
+        }
+        self.primary.get().await
+    }
+
+    pub async fn get_after(&self, lsn: &str, wait: Duration) -> Result<Object, PoolError> {
+        let deadline = tokio::time::Instant::now().checked_add(wait);
+        loop {
+            let healthy = self.healthy();
+            if healthy.is_empty() {
+                break;
+            }
+            for replica in healthy {
+                let Ok(conn) = replica.pool.get().await else {
+                    continue;
+                };
+                let caught_up = conn.query_one("select coalesce(pg_last_wal_replay_lsn() >= $1::text::pg_lsn, false)", &[&lsn]).await
+                    .and_then(|row| row.try_get::<_, bool>(0))
+                    .unwrap_or_default()
+                ;
+                if caught_up {
+                    return Ok(conn);
+                }
+            }
+            if deadline.is_none_or(|deadline| tokio::time::Instant::now() >= deadline) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        metrics::counter!("httpg_replica_lagging_total").increment(1);
+        self.primary.get().await
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replicas.is_empty()
+    }
+
+    pub fn record_metrics(&self) {
+        record_pool_metrics("primary", &self.primary);
+        for replica in &self.replicas {
+            record_pool_metrics(&replica.host, &replica.pool);
+        }
+    }
+
+    fn healthy(&self) -> Vec<&Replica> {
+        let mut healthy: Vec<&Replica> = self.replicas.iter().filter(|r| r.healthy.load(Ordering::Relaxed)).collect();
+        match self.balance {
+            Balance::RoundRobin => if let Some(start) = self.next.fetch_add(1, Ordering::Relaxed).checked_rem(healthy.len()) {
+                healthy.rotate_left(start);
+            },
+            Balance::LeastConnections => healthy.sort_by_key(|r| r.load()),
+        }
+        healthy
+    }
+
+    pub async fn watch(self: Arc<Self>, every: Duration) {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            for replica in self.replicas.iter() {
+                let probe = tokio::time::timeout(every, replica.probe(self.tls.clone())).await;
+                replica.set_healthy(matches!(probe, Ok(Ok(()))));
+            }
+        }
+    }
+}
+
+pub fn record_pool_metrics(host: &str, pool: &Pool) {
+    let status = pool.status();
+    let gauge = |count: usize| u32::try_from(count).unwrap_or(u32::MAX);
+    metrics::gauge!("httpg_pool_size", "host" => host.to_owned()).set(gauge(status.size));
+    metrics::gauge!("httpg_pool_available", "host" => host.to_owned()).set(gauge(status.available));
+    metrics::gauge!("httpg_pool_waiting", "host" => host.to_owned()).set(gauge(status.waiting));
+}
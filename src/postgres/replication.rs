@@ -0,0 +1,262 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, BytesMut};
+use fallible_iterator::FallibleIterator;
+use postgres_protocol::{
+    authentication::{md5_hash, sasl::{ChannelBinding, SCRAM_SHA_256, ScramSha256}},
+    message::{backend::{ErrorResponseBody, Header, Message}, frontend},
+};
+use sqlparser::ast::Ident;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::{TcpStream, UnixStream}};
+use tokio_postgres::tls::{MakeTlsConnect, TlsConnect};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::{HttpgError, postgres::PostgresConfig};
+
+const PORT: u16 = 5432;
+const PG_EPOCH: i64 = 946_684_800_000_000;
+const DUPLICATE_OBJECT: &str = "42710";
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub struct Replication {
+    stream: Box<dyn Stream>,
+    buf: BytesMut,
+    /// Everything up to it was handed over, so the server can release the WAL.
+    flushed: u64,
+}
+
+impl PostgresConfig {
+    pub async fn replication(&self, tls: MakeRustlsConnect) -> Result<Replication, HttpgError> {
+        let mut error = HttpgError::anyhow("no write host");
+        for host in &self.write_host {
+            let timeout = Duration::from_millis(self.connect_timeout);
+            match tokio::time::timeout(timeout, Replication::connect(self, host, tls.clone())).await {
+                Ok(Ok(replication)) => return Ok(replication),
+                Ok(Err(e)) => error = e,
+                Err(_) => error = HttpgError::anyhow(format!("timed out connecting to {host}")),
+            }
+        }
+        Err(error)
+    }
+}
+
+impl Replication {
+    async fn connect(pg: &PostgresConfig, host: &str, mut tls: MakeRustlsConnect) -> Result<Self, HttpgError> {
+        let mut stream: Box<dyn Stream> = match host.starts_with('/') {
+            true => Box::new(UnixStream::connect(format!("{host}/.s.PGSQL.{PORT}")).await?),
+            false => Box::new(TcpStream::connect((host, PORT)).await?),
+        };
+
+        let required = matches!(pg.ssl_mode.as_deref(), Some("require" | "verify-ca" | "verify-full"));
+        if !host.starts_with('/') && pg.ssl_mode.as_deref() != Some("disable") {
+            let mut buf = BytesMut::new();
+            frontend::ssl_request(&mut buf);
+            stream.write_all(&buf).await?;
+            match stream.read_u8().await? {
+                b'S' => {
+                    let connect = MakeTlsConnect::<Box<dyn Stream>>::make_tls_connect(&mut tls, host)
+                        .map_err(|e| HttpgError::anyhow(e.to_string()))?;
+                    stream = Box::new(connect.connect(stream).await?);
+                },
+                _ if required => return Err(HttpgError::anyhow(format!("{host} does not support tls"))),
+                _ => {},
+            }
+        }
+
+        let mut replication = Self { stream, buf: BytesMut::new(), flushed: 0 };
+        replication.startup(pg).await?;
+        Ok(replication)
+    }
+
+    async fn startup(&mut self, pg: &PostgresConfig) -> Result<(), HttpgError> {
+        let mut buf = BytesMut::new();
+        frontend::startup_message([
+            ("user", pg.user.as_str()),
+            ("database", pg.dbname.as_str()),
+            ("application_name", pg.application_name.as_str()),
+            ("replication", "database"),
+        ], &mut buf)?;
+        self.send(buf).await?;
+
+        let password = || pg.password.as_deref().map(str::as_bytes)
+            .ok_or_else(|| HttpgError::anyhow("the server asked for a password"));
+        let mut scram = None;
+        loop {
+            let mut buf = BytesMut::new();
+            match self.read().await? {
+                Message::AuthenticationCleartextPassword => frontend::password_message(password()?, &mut buf)?,
+                Message::AuthenticationMd5Password(body) => {
+                    frontend::password_message(md5_hash(pg.user.as_bytes(), password()?, body.salt()).as_bytes(), &mut buf)?;
+                },
+                Message::AuthenticationSasl(body) => {
+                    if !body.mechanisms().any(|m| Ok(m == SCRAM_SHA_256))? {
+                        return Err(HttpgError::anyhow("no supported sasl mechanism"));
+                    }
+                    let s = ScramSha256::new(password()?, ChannelBinding::unsupported());
+                    frontend::sasl_initial_response(SCRAM_SHA_256, s.message(), &mut buf)?;
+                    scram = Some(s);
+                },
+                Message::AuthenticationSaslContinue(body) => {
+                    let s = scram.as_mut().ok_or_else(|| HttpgError::anyhow("unexpected sasl continue"))?;
+                    s.update(body.data())?;
+                    frontend::sasl_response(s.message(), &mut buf)?;
+                },
+                Message::AuthenticationSaslFinal(body) => {
+                    scram.as_mut().ok_or_else(|| HttpgError::anyhow("unexpected sasl final"))?.finish(body.data())?;
+                },
+                Message::ReadyForQuery(_) => return Ok(()),
+                Message::ErrorResponse(body) => return Err(error(&body)?.1),
+                _ => {},
+            }
+            if !buf.is_empty() {
+                self.send(buf).await?;
+            }
+        }
+    }
+
+    pub async fn create_slot(&mut self, slot: &str, plugin: &str) -> Result<(), HttpgError> {
+        let mut buf = BytesMut::new();
+        frontend::query(&format!("CREATE_REPLICATION_SLOT {} LOGICAL {}", Ident::with_quote('"', slot), Ident::with_quote('"', plugin)), &mut buf)?;
+        self.send(buf).await?;
+
+        let mut result = Ok(());
+        loop {
+            match self.read().await? {
+                Message::ReadyForQuery(_) => return result,
+                Message::ErrorResponse(body) => match error(&body)? {
+                    (code, _) if code == DUPLICATE_OBJECT => {},
+                    (_, e) => result = Err(e),
+                },
+                _ => {},
+            }
+        }
+    }
+
+    pub async fn start(&mut self, slot: &str, options: &[(&str, &str)]) -> Result<(), HttpgError> {
+        let options = options.iter()
+            .map(|(name, value)| format!("{} '{}'", Ident::with_quote('"', *name), value.replace('\'', "''")))
+            .collect::<Vec<_>>();
+        let options = match options.is_empty() {
+            true => String::new(),
+            false => format!(" ({})", options.join(", ")),
+        };
+        let mut buf = BytesMut::new();
+        frontend::query(&format!("START_REPLICATION SLOT {} LOGICAL 0/0{options}", Ident::with_quote('"', slot)), &mut buf)?;
+        self.send(buf).await?;
+
+        // CopyBothResponse isn't known to postgres-protocol
+        loop {
+            match Header::parse(&self.buf)? {
+                Some(header) if header.tag() == b'W' => {
+                    let len = usize::try_from(header.len()).map_err(|e| HttpgError::anyhow(e.to_string()))?.saturating_add(1);
+                    if self.buf.len() >= len {
+                        let _ = self.buf.split_to(len);
+                        return Ok(());
+                    }
+                },
+                Some(_) => match self.read().await? {
+                    Message::ErrorResponse(body) => return Err(error(&body)?.1),
+                    _ => continue,
+                },
+                None => {},
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(HttpgError::anyhow("replication connection closed"));
+            }
+        }
+    }
+
+    pub async fn next(&mut self, status_interval: Duration) -> Result<Option<String>, HttpgError> {
+        loop {
+            let message = match tokio::time::timeout(status_interval, self.read()).await {
+                Ok(message) => message?,
+                Err(_) => {
+                    self.status().await?;
+                    continue;
+                },
+            };
+            match message {
+                Message::CopyData(body) => match body.data().split_first() {
+                    // XLogData: start, end, clock, then the plugin's output
+                    Some((b'w', rest)) => {
+                        let start = be_u64(rest, 0)?;
+                        self.flushed = self.flushed.max(start);
+                        let data = rest.get(24..).ok_or_else(|| HttpgError::anyhow("short xlog data"))?;
+                        return Ok(Some(String::from_utf8_lossy(data).into_owned()));
+                    },
+                    // keepalive: end, clock, reply requested
+                    Some((b'k', rest)) => {
+                        // everything before was sent already
+                        self.flushed = self.flushed.max(be_u64(rest, 0)?);
+                        if rest.get(16) == Some(&1) {
+                            self.status().await?;
+                        }
+                    },
+                    _ => {},
+                },
+                Message::CopyDone => return Ok(None),
+                Message::ErrorResponse(body) => return Err(error(&body)?.1),
+                _ => {},
+            }
+        }
+    }
+
+    async fn status(&mut self) -> Result<(), HttpgError> {
+        let clock = SystemTime::now().duration_since(UNIX_EPOCH).ok()
+            .and_then(|d| i64::try_from(d.as_micros()).ok())
+            .unwrap_or_default()
+            .saturating_sub(PG_EPOCH);
+        let mut body = BytesMut::new();
+        body.put_u8(b'r');
+        for lsn in [self.flushed; 3] {
+            body.put_u64(lsn);
+        }
+        body.put_i64(clock);
+        body.put_u8(0);
+
+        let mut buf = BytesMut::new();
+        frontend::CopyData::new(body)?.write(&mut buf);
+        self.send(buf).await
+    }
+
+    async fn send(&mut self, buf: BytesMut) -> Result<(), HttpgError> {
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<Message, HttpgError> {
+        loop {
+            if let Some(message) = Message::parse(&mut self.buf)? {
+                return Ok(message);
+            }
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(HttpgError::anyhow("replication connection closed"));
+            }
+        }
+    }
+}
+
+fn be_u64(bytes: &[u8], at: usize) -> Result<u64, HttpgError> {
+    at.checked_add(8)
+        .and_then(|end| bytes.get(at..end))
+        .and_then(|b| b.try_into().ok())
+        .map(u64::from_be_bytes)
+        .ok_or_else(|| HttpgError::anyhow("short replication message"))
+}
+
+fn error(body: &ErrorResponseBody) -> Result<(String, HttpgError), HttpgError> {
+    let mut fields = body.fields();
+    let (mut code, mut message) = (String::new(), String::new());
+    while let Some(field) = fields.next()? {
+        match field.type_() {
+            b'C' => code = String::from_utf8_lossy(field.value_bytes()).into_owned(),
+            b'M' => message = String::from_utf8_lossy(field.value_bytes()).into_owned(),
+            _ => {},
+        }
+    }
+    let e = HttpgError::anyhow(format!("{code}: {message}"));
+    Ok((code, e))
+}
@@ -1,15 +1,16 @@
-use std::{collections::HashMap, pin::Pin, task::{Context, Poll}};
+use std::{collections::{BTreeMap, HashMap}, pin::Pin, task::{Context, Poll}};
 
-use axum::{body::Body, http::{HeaderName, HeaderValue, StatusCode, header::{CACHE_CONTROL, CONTENT_TYPE}}, response::{IntoResponse, Redirect, Response}};
+use axum::{body::Body, http::{HeaderName, HeaderValue, StatusCode, header::{CACHE_CONTROL, CONTENT_RANGE, CONTENT_TYPE, LINK}}, response::{IntoResponse, Redirect, Response}};
 use bytes::{BufMut, BytesMut};
 use futures::{Stream, StreamExt, stream};
 use http::HeaderMap;
 use postgres_types::{Type};
 use tokio_postgres::{Row, RowStream};
 
-use crate::{HttpgError, extract::query::Query, postgres::QueryGuard};
+use crate::{HttpgError, extract::query::Query, postgres::QueryGuard, sql::Page};
 
 pub mod compress_stream;
+pub mod sse;
 
 pub struct HttpResult {
     pub query: Query,
@@ -31,9 +32,10 @@ impl CancelStream {
         }
     }
 
-    pub fn from_vec(vec: Vec<Row>, guard: QueryGuard) -> Self
+    pub fn from_vec(vec: Vec<Row>, mut guard: QueryGuard) -> Self
     {
-        
+        // the rows are all fetched and the connection may already serve someone else
+        guard.finished = true;
         Self {
             inner: Box::pin(stream::iter(vec.into_iter().map(Ok).collect::<Vec::<_>>())),
             guard,
@@ -131,10 +133,20 @@ impl IntoResponse for HttpResult {
             _ => HeaderValue::from_static("application/octet-stream"),
         });
 
+        if let Some(Ok(range)) = self.query.count.map(|count| content_range(self.query.page.as_ref(), count).parse::<HeaderValue>()) {
+            headers.insert(CONTENT_RANGE, range);
+        }
+
         if let Some(Ok(cache_control)) = self.query.cache_control.map(|a| a.parse::<HeaderValue>()) {
             headers.insert(CACHE_CONTROL, cache_control);
         }
 
+        for next in self.query.page.iter().flat_map(|page| page.values()).filter_map(|p| p.next.as_ref()) {
+            if let Ok(link) = HeaderValue::from_str(&format!("<{next}>; rel=\"next\"")) {
+                headers.append(LINK, link);
+            }
+        }
+
         // headers.insert("X-Accel-Buffering".parse::<HeaderName>().unwrap(), "no".parse::<HeaderValue>().unwrap());
 
         let mut iter = futures::executor::block_on_stream(self.rows);
@@ -172,6 +184,21 @@ impl IntoResponse for HttpResult {
     }
 }
 
+/// `0-24/100` for the first page of 25 rows, `*/100` when the range is unknown (keyset, no page limit, the query's own limit) or empty.
+fn content_range(page: Option<&BTreeMap<String, Page>>, count: i64) -> String {
+    let page = page.and_then(|page| page.values().next());
+    let total = u64::try_from(count).unwrap_or_default();
+    let first = page.and_then(|page| page.after.is_empty().then_some(page.offset.unwrap_or_default()));
+    let last = page.and_then(|page| page.limit)
+        .zip(first)
+        .and_then(|(limit, first)| first.saturating_add(limit).min(total).checked_sub(1));
+
+    match (first, last) {
+        (Some(first), Some(last)) if first <= last => format!("{first}-{last}/{count}"),
+        _ => format!("*/{count}"),
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod tests {
@@ -184,7 +211,8 @@ mod tests {
     #[tokio::test]
     async fn test_into_response_status() {
         let cfg = crate::postgres::PostgresConfig::parse();
-        let conn = cfg.read_pool().unwrap().get().await.unwrap();
+        let tls = cfg.tls().unwrap();
+        let conn = cfg.read_pool(tls.clone(), cfg.write_pool(tls.clone()).unwrap()).unwrap().get().await.unwrap();
 
         let query = Query {
             sql: Some("select 'a'::text".into()),
@@ -200,6 +228,7 @@ mod tests {
 
         let guard = crate::postgres::QueryGuard {
             cancel_token: conn.cancel_token(),
+            tls,
             finished: false,
         };
 
@@ -213,4 +242,16 @@ mod tests {
 
         assert_eq!(body.collect().await.unwrap().to_bytes(), "a\n".to_string().as_bytes());
     }
-} 
+
+    #[test]
+    fn test_content_range() {
+        let page = |limit, offset| std::collections::BTreeMap::from([("p".to_string(), crate::sql::Page { limit, offset, ..Default::default() })]);
+
+        assert_eq!(response::content_range(None, 100), "*/100");
+        assert_eq!(response::content_range(Some(&page(None, None)), 100), "*/100");
+        assert_eq!(response::content_range(Some(&page(Some(25), None)), 100), "0-24/100");
+        assert_eq!(response::content_range(Some(&page(Some(25), Some(90))), 100), "90-99/100");
+        assert_eq!(response::content_range(Some(&page(Some(25), Some(100))), 100), "*/100");
+        assert_eq!(response::content_range(Some(&page(Some(25), None)), 0), "*/0");
+    }
+}
@@ -0,0 +1,140 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use axum::response::sse::Event;
+use bytes::{BufMut, BytesMut};
+use futures::StreamExt;
+use serde_json::{Value, json};
+
+use crate::{HttpgError, response::CancelStream};
+
+/// JSON objects may set `event`, `id`, `retry` (ms) and `data`, anything else is sent as data.
+pub fn event(payload: &str, rendered: Option<String>) -> (Option<String>, Event) {
+    let Ok(serde_json::Value::Object(mut object)) = serde_json::from_str(payload) else {
+        return (None, Event::default().data(rendered.as_deref().unwrap_or(payload)));
+    };
+    if !["event", "id", "retry", "data"].iter().any(|key| object.contains_key(*key)) {
+        return (None, Event::default().data(rendered.as_deref().unwrap_or(payload)));
+    }
+
+    // these can't span lines in the event stream
+    let field = |value: Option<serde_json::Value>| match value {
+        Some(serde_json::Value::String(s)) => Some(s),
+        Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    }.filter(|s| !s.contains(['\n', '\r', '\0']));
+
+    let id = field(object.remove("id"));
+    let mut event = match (rendered, object.remove("data")) {
+        (Some(rendered), _) => Event::default().data(rendered),
+        (None, Some(serde_json::Value::String(data))) => Event::default().data(data),
+        (None, Some(data)) => Event::default().data(data.to_string()),
+        (None, None) => Event::default().data(""),
+    };
+    if let Some(name) = field(object.remove("event")) {
+        event = event.event(name);
+    }
+    if let Some(id) = &id {
+        event = event.id(id);
+    }
+    if let Some(retry) = object.remove("retry").as_ref().and_then(serde_json::Value::as_u64) {
+        event = event.retry(Duration::from_millis(retry));
+    }
+    (id, event)
+}
+
+pub fn reference(payload: &str) -> Option<String> {
+    let Ok(Value::Object(mut object)) = serde_json::from_str(payload) else {
+        return None;
+    };
+    match (object.remove("ref"), object.is_empty()) {
+        (Some(Value::String(reference)), true) => Some(reference),
+        _ => None,
+    }
+}
+
+pub async fn render(mut rows: CancelStream) -> Result<String, HttpgError> {
+    let mut body = BytesMut::new();
+    while let Some(row) = rows.next().await {
+        if let Some(b) = row?.body {
+            body.put(b);
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+pub fn diff(key: &str, old: &[Value], new: &[Value]) -> Option<Value> {
+    fn by_key<'a>(key: &str, rows: &'a [Value]) -> BTreeMap<String, &'a Value> {
+        rows.iter().map(|row| (row.get(key).map(Value::to_string).unwrap_or_default(), row)).collect()
+    }
+    let (old, new) = (by_key(key, old), by_key(key, new));
+
+    let mut insert = vec![];
+    let mut update = vec![];
+    for (k, row) in &new {
+        match old.get(k) {
+            None => insert.push(*row),
+            Some(previous) if previous != row => update.push(*row),
+            Some(_) => {},
+        }
+    }
+    let delete: Vec<_> = old.iter()
+        .filter(|(k, _)| !new.contains_key(*k))
+        .map(|(_, row)| row.get(key).cloned().unwrap_or_default())
+        .collect();
+
+    match insert.is_empty() && update.is_empty() && delete.is_empty() {
+        true => None,
+        false => Some(json!({"insert": insert, "update": update, "delete": delete})),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use axum::response::{IntoResponse, Sse, sse::Event};
+    use http_body_util::BodyExt;
+
+    use serde_json::json;
+
+    use super::{diff, event, reference};
+
+    async fn stream(events: Vec<Event>) -> String {
+        let sse = Sse::new(futures::stream::iter(events.into_iter().map(Ok::<_, std::convert::Infallible>)));
+        let body = sse.into_response().into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_event_convention() {
+        let (id, message) = event(r#"{"event": "message", "id": 42, "retry": 1000, "data": {"text": "hi"}}"#, None);
+        assert_eq!(id.as_deref(), Some("42"));
+        let (id, raw) = event(r#"{"text": "hi"}"#, None);
+        assert_eq!(id, None);
+        let (_, unsafe_id) = event("{\"id\": \"a\\nb\", \"data\": \"x\"}", None);
+
+        assert_eq!(
+            stream(vec![message, raw, unsafe_id, event("plain", None).1, event(r#"{"id": 5, "data": 1}"#, Some("<p>rendered</p>".into())).1]).await,
+            "data: {\"text\":\"hi\"}\nevent: message\nid: 42\nretry: 1000\n\ndata: {\"text\": \"hi\"}\n\ndata: x\n\ndata: plain\n\ndata: <p>rendered</p>\nid: 5\n\n",
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = [json!({"id": 1, "lat": 0}), json!({"id": 2, "lat": 0}), json!({"id": 3, "lat": 0})];
+        let new = [json!({"id": 1, "lat": 0}), json!({"id": 2, "lat": 1}), json!({"id": 4, "lat": 0})];
+        assert_eq!(diff("id", &old, &new), Some(json!({
+            "insert": [{"id": 4, "lat": 0}],
+            "update": [{"id": 2, "lat": 1}],
+            "delete": [3],
+        })));
+        assert_eq!(diff("id", &old, &old), None);
+    }
+
+    #[test]
+    fn test_reference() {
+        assert_eq!(reference(r#"{"ref": "blog.post:42"}"#).as_deref(), Some("blog.post:42"));
+        assert_eq!(reference(r#"{"ref": "blog.post:42", "data": 1}"#), None);
+        assert_eq!(reference(r#"{"ref": 42}"#), None);
+        assert_eq!(reference("ref"), None);
+    }
+}
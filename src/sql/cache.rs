@@ -0,0 +1,94 @@
+use std::{collections::BTreeMap, num::NonZeroUsize, sync::Mutex};
+
+use lru::LruCache;
+
+use crate::{error::HttpgError, sql::{Order, Page}};
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Key {
+    sql: String,
+    order: Option<Order>,
+    append: bool,
+    page: Option<BTreeMap<String, Page>>,
+}
+
+type Parsed = Result<Option<String>, Option<String>>;
+
+pub struct SqlCache(Option<Mutex<LruCache<Key, Parsed>>>);
+
+impl SqlCache {
+    pub fn new(capacity: usize) -> Self {
+        Self(NonZeroUsize::new(capacity).map(|capacity| Mutex::new(LruCache::new(capacity))))
+    }
+
+    pub fn parse(
+        &self,
+        sql: Option<String>,
+        order: &Option<Order>,
+        append: bool,
+        page: &Option<BTreeMap<String, Page>>,
+        parse: impl FnOnce(Option<String>) -> Result<Option<String>, HttpgError>,
+    ) -> Result<Option<String>, HttpgError> {
+        let (Some(cache), Some(sql)) = (&self.0, &sql) else {
+            return parse(sql);
+        };
+        let key = Key {
+            sql: sql.to_owned(),
+            order: order.to_owned(),
+            append,
+            page: page.as_ref().map(|page| page.iter()
+                .map(|(rel, page)| (rel.to_owned(), Page { next: None, keyset: None, ..page.to_owned() }))
+                .collect()
+            ),
+        };
+
+        if let Some(cached) = cache.lock().ok().and_then(|mut cache| cache.get(&key).cloned()) {
+            metrics::counter!("httpg_sql_cache_hits_total").increment(1);
+            return cached.map_err(|reason| HttpgError::RefusedSql { query: key.sql, reason });
+        }
+        metrics::counter!("httpg_sql_cache_misses_total").increment(1);
+
+        let result = parse(sql.to_owned().into());
+        let cached = match &result {
+            Ok(sql) => Some(Ok(sql.to_owned())),
+            Err(HttpgError::RefusedSql { reason, .. }) => Some(Err(reason.to_owned())),
+            Err(_) => None,
+        };
+        if let (Some(cached), Ok(mut cache)) = (cached, cache.lock()) {
+            cache.put(key, cached);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::error::HttpgError;
+
+    use super::SqlCache;
+
+    #[test]
+    fn test_cache_hit() {
+        let cache = SqlCache::new(2);
+        let calls = Cell::new(0);
+        let parse = |sql: Option<String>| {
+            calls.set(calls.get() + 1);
+            match sql.as_deref() {
+                Some("drop table post") => Err(HttpgError::RefusedSql { query: "drop table post".into(), reason: Some("only DML".into()) }),
+                _ => Ok(sql),
+            }
+        };
+
+        for _ in 0..2 {
+            assert_eq!(cache.parse(Some("select 1".into()), &None, false, &None, parse).unwrap(), Some("select 1".into()));
+            assert!(matches!(
+                cache.parse(Some("drop table post".into()), &None, false, &None, parse),
+                Err(HttpgError::RefusedSql { reason: Some(_), .. })
+            ));
+        }
+        assert_eq!(calls.get(), 2);
+    }
+}
@@ -1,13 +1,144 @@
-use sqlparser::{ast::{Expr, Function, Ident, OrderBy, OrderByExpr, Query, SetExpr, Spanned, Statement, TableFactor, TableWithJoins, Visitor, VisitorMut}};
-use std::{collections::BTreeMap, ops::{ControlFlow, Not}};
+pub mod cache;
+
+use serde::{Deserialize, Serialize};
+use sqlparser::{ast::{BinaryOperator, CastKind, DataType, Expr, Function, Ident, LimitClause, Offset, OffsetRows, OrderBy, OrderByExpr, OrderByKind, OrderByOptions, Query, Select, SelectItem, SetExpr, Statement, TableFactor, TableWithJoins, Value, Visitor, VisitorMut}, dialect::PostgreSqlDialect, keywords::Keyword, parser::Parser, tokenizer::{Token, Tokenizer}};
+use std::{collections::BTreeMap, ops::ControlFlow};
 
 use crate::error::HttpgError;
 
-pub struct VisitOrderBy(pub BTreeMap<String, serde_json::Value>);
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct OrderKey {
+    pub col: String,
+    pub asc: bool,
+    pub nulls_first: Option<bool>,
+}
+
+pub type Order = BTreeMap<String, Vec<OrderKey>>;
+
+impl OrderKey {
+    pub fn parse(rel: &str, value: &serde_json::Value) -> Result<Vec<Self>, HttpgError> {
+        let invalid = |reason: String| HttpgError::InvalidOrder { rel: rel.to_string(), reason };
+
+        let key = |col: &str, options: &str| {
+            if col.is_empty() {
+                return Err(invalid("empty column".to_string()));
+            }
+            let options = options.to_lowercase();
+            let options: Vec<&str> = options.split_whitespace().collect();
+            let (asc, nulls) = match options.as_slice() {
+                ["asc", nulls @ ..] => (true, nulls),
+                ["desc", nulls @ ..] => (false, nulls),
+                nulls => (true, nulls),
+            };
+            let nulls_first = match nulls {
+                [] => None,
+                ["nulls", "first"] => Some(true),
+                ["nulls", "last"] => Some(false),
+                _ => return Err(invalid(format!("expected [asc|desc] [nulls first|last] for {col}"))),
+            };
+            Ok(Self { col: col.to_string(), asc, nulls_first })
+        };
+
+        match value {
+            serde_json::Value::Object(cols) => cols.iter()
+                .map(|(col, options)| key(col, options.as_str().ok_or(invalid(format!("expected a string for {col}")))?))
+                .collect(),
+            serde_json::Value::Array(cols) => cols.iter()
+                .map(|col| {
+                    let col = col.as_str().ok_or(invalid("expected order[rel][]=col asc|desc".to_string()))?.trim();
+                    let (col, options) = col.split_once(char::is_whitespace).unwrap_or((col, ""));
+                    key(col, options)
+                })
+                .collect(),
+            _ => Err(invalid("expected order[rel][col]=asc|desc or order[rel][]=col asc|desc".to_string())),
+        }
+    }
+}
+
+pub struct VisitOrderBy<'a> {
+    pub order: &'a Order,
+    pub append: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct Page {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+    #[serde(skip)]
+    pub keyset: Option<Keyset>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
+pub struct Keyset {
+    pub sql: String,
+    pub next: String,
+}
+
+impl Page {
+    pub fn parse(rel: &str, value: &serde_json::Value) -> Result<Self, HttpgError> {
+        let invalid = |reason: &str| HttpgError::InvalidPage { rel: rel.to_string(), reason: reason.to_string() };
+
+        let number = |key: &str| match value.get(key) {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(serde_json::Value::Number(n)) => n.as_u64().map(Some).ok_or(invalid(key)),
+            Some(serde_json::Value::String(s)) => s.parse().map(Some).or(Err(invalid(key))),
+            Some(_) => Err(invalid(key)),
+        };
+        let scalar = |v: &serde_json::Value| match v {
+            serde_json::Value::String(s) => Ok(s.to_owned()),
+            serde_json::Value::Number(n) => Ok(n.to_string()),
+            _ => Err(invalid("after")),
+        };
+
+        if !value.is_object() {
+            return Err(invalid("expected page[rel][limit|offset|after]"));
+        }
+
+        let after = match value.get("after") {
+            None | Some(serde_json::Value::Null) => vec![],
+            Some(serde_json::Value::Array(a)) => a.iter().map(scalar).collect::<Result<_, _>>()?,
+            Some(v) => vec![scalar(v)?],
+        };
+
+        let page = Self {
+            limit: number("limit")?,
+            offset: number("offset")?,
+            after,
+            next: None,
+            keyset: None,
+        };
+        if page.offset.is_some() && !page.after.is_empty() {
+            return Err(invalid("offset and after are exclusive"));
+        }
+        Ok(page)
+    }
+}
+
+pub struct VisitPage<'a> {
+    pub page: &'a BTreeMap<String, Page>,
+    pub order: Option<&'a Order>,
+}
 
 #[derive(Debug)]
 pub struct AllowList(pub Result<(), HttpgError>);
 
+pub struct CountBase<'a> {
+    pub page: Option<&'a BTreeMap<String, Page>>,
+    pub base: Option<Query>,
+}
+
+pub struct KeysetBase<'a> {
+    pub rel: &'a str,
+    pub keys: &'a [OrderKey],
+    pub base: Option<Query>,
+}
+
 impl Visitor for AllowList {
     type Break = ();
 
@@ -65,11 +196,80 @@ impl Visitor for AllowList {
     }
 }
 
-impl VisitorMut for VisitOrderBy {
+impl Visitor for CountBase<'_> {
     type Break = ();
 
-    fn post_visit_query(&mut self, expr: &mut Query) -> ControlFlow<Self::Break> {
-        expr.order_by = match &*expr.body {
+    fn pre_visit_statement(&mut self, statement: &Statement) -> ControlFlow<Self::Break> {
+        match (self.page, statement) {
+            (Some(_), _) => ControlFlow::Continue(()),
+            (None, Statement::Query(query)) => {
+                self.base = Some(*query.to_owned());
+                ControlFlow::Break(())
+            },
+            (None, _) => ControlFlow::Break(()),
+        }
+    }
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<Self::Break> {
+        if let (Some(page), SetExpr::Select(select)) = (self.page, &*query.body)
+            && page.keys().any(|rel| selects_from(select, rel))
+        {
+            self.base = Some(query.to_owned());
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+impl CountBase<'_> {
+    pub fn sql(self) -> Option<String> {
+        self.base.map(|mut query| {
+            query.order_by = None;
+            query.limit_clause = None;
+            query.fetch = None;
+            query.to_string()
+        })
+    }
+}
+
+impl Visitor for KeysetBase<'_> {
+    type Break = ();
+
+    fn pre_visit_query(&mut self, query: &Query) -> ControlFlow<Self::Break> {
+        if let SetExpr::Select(select) = &*query.body
+            && selects_from(select, self.rel)
+        {
+            self.base = Some(query.to_owned());
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+impl KeysetBase<'_> {
+    pub fn sql(self) -> Option<String> {
+        let mut query = self.base?;
+        let SetExpr::Select(select) = query.body.as_mut() else {
+            return None;
+        };
+        select.projection = self.keys.iter()
+            .map(|key| SelectItem::UnnamedExpr(Expr::Cast {
+                kind: CastKind::DoubleColon,
+                expr: Box::new(column(self.rel, &key.col)),
+                data_type: DataType::Text,
+                array: false,
+                format: None,
+            }))
+            .collect();
+        Some(query.to_string())
+    }
+}
+
+impl VisitorMut for VisitOrderBy<'_> {
+    type Break = ();
+
+    fn post_visit_query(&mut self, query: &mut Query) -> ControlFlow<Self::Break> {
+        let exprs = match &*query.body {
             SetExpr::Select(select) => {
                 self.order_by(select)
             }
@@ -80,55 +280,313 @@ impl VisitorMut for VisitOrderBy {
                 (_, SetExpr::Select(select)) => {
                     self.order_by(select)
                 }
-                _ => None
+                _ => vec![]
             }
-            _ => None
+            _ => vec![]
         };
+        if exprs.is_empty() {
+            return ControlFlow::Continue(());
+        }
+        query.order_by = Some(match query.order_by.take() {
+            Some(OrderBy { kind: OrderByKind::Expressions(mut existing), interpolate }) if self.append => {
+                existing.extend(exprs);
+                OrderBy { kind: OrderByKind::Expressions(existing), interpolate }
+            }
+            _ => OrderBy { kind: OrderByKind::Expressions(exprs), interpolate: None },
+        });
         ControlFlow::Continue(())
     }
 }
 
-impl VisitOrderBy {
-    pub(crate) fn order_by(&mut self, select: &sqlparser::ast::Select) -> Option<OrderBy> {
-        let exprs: Vec<OrderByExpr> = self.0.iter()
-            .filter(|(rel, _)| {
-                select.from.iter().any(|from| {
-                    match from {
-                        TableWithJoins { relation: TableFactor::Table { alias: Some(alias), .. }, .. } => {
-                            &&alias.to_string() == rel
-                        }
-                        _ => false
-                    }
-                })
-        
-            })
-            .flat_map(|(rel, cols)| {
-                cols.as_object().unwrap().into_iter().map(move |(col, asc)| {
-                    OrderByExpr {
-                        expr: Expr::Identifier(Ident {
-                            value: format!("{rel}.{col}"),
-                            quote_style: None,
-                            span: select.span(),
-                        }),
-                        options: sqlparser::ast::OrderByOptions {
-                            // sort: Some(match asc.as_str() {
-                            //     Some("desc") => sqlparser::ast::OrderBySort::Asc,
-                            //     _ => sqlparser::ast::OrderBySort::Desc,
-                            // }),
-                            asc: Some(matches!(asc.as_str(), Some("desc")).not()),
-                            nulls_first: None,
-                        },
-                        with_fill: None,
-                    }
-                })
-            }).collect()
+impl VisitOrderBy<'_> {
+    fn order_by(&self, select: &Select) -> Vec<OrderByExpr> {
+        self.order.iter()
+            .filter(|(rel, _)| selects_from(select, rel))
+            .flat_map(|(rel, keys)| keys.iter().map(move |key| OrderByExpr {
+                expr: column(rel, &key.col),
+                options: OrderByOptions {
+                    asc: Some(key.asc),
+                    nulls_first: key.nulls_first,
+                },
+                with_fill: None,
+            }))
+            .collect()
+    }
+}
+
+impl VisitorMut for VisitPage<'_> {
+    type Break = HttpgError;
+
+    fn post_visit_query(&mut self, query: &mut Query) -> ControlFlow<Self::Break> {
+        let SetExpr::Select(select) = query.body.as_mut() else {
+            return ControlFlow::Continue(());
+        };
+        let pages: Vec<(&String, &Page)> = self.page.iter().filter(|(rel, _)| selects_from(select, rel)).collect();
+        for (rel, page) in pages {
+            if !page.after.is_empty() {
+                match self.keyset(rel, page) {
+                    Ok(predicate) => {
+                        select.selection = Some(match select.selection.take() {
+                            Some(selection) => Expr::BinaryOp {
+                                left: Box::new(Expr::Nested(Box::new(selection))),
+                                op: BinaryOperator::And,
+                                right: Box::new(predicate),
+                            },
+                            None => predicate,
+                        });
+                    },
+                    Err(e) => return ControlFlow::Break(e),
+                }
+            }
+
+            let (limit, offset) = match query.limit_clause.take() {
+                Some(LimitClause::LimitOffset { limit, offset, .. }) => (limit, offset),
+                Some(LimitClause::OffsetCommaLimit { offset, limit }) => (Some(limit), Some(Offset { value: offset, rows: OffsetRows::None })),
+                None => (None, None),
+            };
+            query.limit_clause = Some(LimitClause::LimitOffset {
+                limit: page.limit.map(number).or(limit),
+                offset: page.offset.map(|o| Offset { value: number(o), rows: OffsetRows::None }).or(offset),
+                limit_by: vec![],
+            });
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+impl VisitPage<'_> {
+    /// `(a, b) > ('1', '2')` when every key sorts the same way,
+    /// `a > '1' or (a = '1' and b < '2')` otherwise.
+    fn keyset(&self, rel: &str, page: &Page) -> Result<Expr, HttpgError> {
+        let invalid = |reason: &str| HttpgError::InvalidPage { rel: rel.to_string(), reason: reason.to_string() };
+
+        let keys: Vec<(Expr, bool)> = self.order
+            .and_then(|order| order.get(rel))
+            .ok_or(invalid("keyset pagination needs an order"))?
+            .iter()
+            .map(|key| (column(rel, &key.col), key.asc))
+            .collect()
+        ;
+        if keys.len() != page.after.len() {
+            return Err(invalid("after needs one value per order column"));
+        }
+        let values: Vec<Expr> = page.after.iter()
+            .map(|v| Expr::value(Value::SingleQuotedString(v.to_owned())))
+            .collect()
         ;
+        let op = |asc: bool| if asc { BinaryOperator::Gt } else { BinaryOperator::Lt };
+        let binary = |left: Expr, op: BinaryOperator, right: Expr| Expr::BinaryOp {
+            left: Box::new(left),
+            op,
+            right: Box::new(right),
+        };
+
+        if let [(key, asc)] = keys.as_slice() {
+            return Ok(binary(key.to_owned(), op(*asc), values.first().cloned().ok_or(invalid("after"))?));
+        }
+        if keys.iter().all(|(_, asc)| *asc) || keys.iter().all(|(_, asc)| !*asc) {
+            let asc = keys.iter().all(|(_, asc)| *asc);
+            return Ok(binary(
+                Expr::Tuple(keys.into_iter().map(|(key, _)| key).collect()),
+                op(asc),
+                Expr::Tuple(values),
+            ));
+        }
+
+        keys.iter().zip(values.iter()).enumerate()
+            .map(|(i, ((key, asc), value))| {
+                keys.iter().zip(values.iter()).take(i)
+                    .fold(binary(key.to_owned(), op(*asc), value.to_owned()), |acc, ((key, _), value)| {
+                        binary(binary(key.to_owned(), BinaryOperator::Eq, value.to_owned()), BinaryOperator::And, acc)
+                    })
+            })
+            .reduce(|acc, e| binary(acc, BinaryOperator::Or, Expr::Nested(Box::new(e))))
+            .ok_or(invalid("after"))
+    }
+}
+
+/// Unaliased tables are matched by their bare name.
+fn selects_from(select: &Select, rel: &str) -> bool {
+    select.from.iter().any(|from| joins_rel(from, rel))
+}
 
-        (!exprs.is_empty()).then_some(OrderBy {
-            kind: sqlparser::ast::OrderByKind::Expressions(exprs),
-            interpolate: None,
+fn joins_rel(from: &TableWithJoins, rel: &str) -> bool {
+    std::iter::once(&from.relation)
+        .chain(from.joins.iter().map(|join| &join.relation))
+        .any(|relation| match relation {
+            TableFactor::Table { alias: Some(alias), .. }
+            | TableFactor::Derived { alias: Some(alias), .. }
+            | TableFactor::TableFunction { alias: Some(alias), .. }
+            | TableFactor::Function { alias: Some(alias), .. }
+            | TableFactor::UNNEST { alias: Some(alias), .. } => alias.name.value == rel,
+            TableFactor::Table { name, alias: None, .. } => name.0.last()
+                .and_then(|part| part.as_ident())
+                .is_some_and(|ident| ident.value == rel),
+            TableFactor::NestedJoin { alias: Some(alias), .. } => alias.name.value == rel,
+            TableFactor::NestedJoin { table_with_joins, alias: None } => joins_rel(table_with_joins, rel),
+            _ => false,
         })
+}
+
+/// Refuses anything but `set local ...`, so biscuit statements can't leak into the pooled session.
+/// Checked on tokens, as the AST doesn't know `set local role to ...`.
+pub fn set_local_only(sql: &str) -> Result<(), HttpgError> {
+    let refused = |reason: &str| HttpgError::RefusedSql { query: sql.to_owned(), reason: Some(reason.to_owned()) };
+    let tokens: Vec<Token> = Tokenizer::new(&PostgreSqlDialect{}, sql).tokenize()
+        .map_err(|e| refused(&e.to_string()))?
+        .into_iter()
+        .filter(|token| !matches!(token, Token::Whitespace(_)))
+        .collect()
+    ;
+    let set_local = |statement: &[Token]| match statement {
+        [] => true,
+        [Token::Word(set), Token::Word(local), _, ..] => set.keyword == Keyword::SET && local.keyword == Keyword::LOCAL,
+        _ => false,
+    };
+    match tokens.split(|token| *token == Token::SemiColon).all(set_local) {
+        true => Ok(()),
+        false => Err(refused("only set local statements are allowed")),
     }
 }
 
+/// Whether the sql is a single query, the only statement `explain` takes among the allowed ones (not `call`).
+pub fn explainable(sql: &str) -> bool {
+    matches!(Parser::parse_sql(&PostgreSqlDialect{}, sql).as_deref(), Ok([Statement::Query(_)]))
+}
+
+fn column(rel: &str, col: &str) -> Expr {
+    Expr::CompoundIdentifier(vec![Ident::new(rel), Ident::with_quote('"', col)])
+}
+
+fn number(n: u64) -> Expr {
+    Expr::value(Value::Number(n.to_string(), false))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+    use sqlparser::{ast::{Visit, VisitMut}, dialect::PostgreSqlDialect, parser::Parser};
+
+    use super::{CountBase, KeysetBase, Order, OrderKey, Page, VisitOrderBy, VisitPage, explainable, set_local_only};
+
+    fn order(order: serde_json::Value) -> Order {
+        order.as_object().unwrap().iter()
+            .map(|(rel, keys)| (rel.to_owned(), OrderKey::parse(rel, keys).unwrap()))
+            .collect()
+    }
+
+    fn rewrite(sql: &str, order: Order, append: bool, page: serde_json::Value) -> String {
+        let page: BTreeMap<String, Page> = page.as_object().unwrap().iter()
+            .map(|(rel, p)| (rel.to_owned(), Page::parse(rel, p).unwrap()))
+            .collect();
+        let mut statements = Parser::parse_sql(&PostgreSqlDialect{}, sql).unwrap();
+        let _ = VisitMut::visit(&mut statements, &mut VisitOrderBy { order: &order, append });
+        assert!(VisitMut::visit(&mut statements, &mut VisitPage { page: &page, order: Some(&order) }).is_continue());
+        statements.first().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_order_joins_and_subqueries() {
+        assert_eq!(
+            rewrite(
+                "select string_agg(x.title, '') from (select p.title from post p join author a using (author_id) order by p.id) x",
+                order(json!({"a": ["name desc nulls last", "author_id"]})),
+                true,
+                json!({}),
+            ),
+            r#"SELECT string_agg(x.title, '') FROM (SELECT p.title FROM post p JOIN author a USING(author_id) ORDER BY p.id, a."name" DESC NULLS LAST, a."author_id" ASC) x"#,
+        );
+        assert_eq!(
+            rewrite(
+                "with recent as (select * from post order by at desc) select title from recent",
+                order(json!({"recent": {"title": "asc"}})),
+                false,
+                json!({}),
+            ),
+            r#"WITH recent AS (SELECT * FROM post ORDER BY at DESC) SELECT title FROM recent ORDER BY recent."title" ASC"#,
+        );
+    }
+
+    #[test]
+    fn test_order_malformed() {
+        assert!(OrderKey::parse("r", &json!("asc")).is_err());
+        assert!(OrderKey::parse("r", &json!({"id": "sideways"})).is_err());
+        assert!(OrderKey::parse("r", &json!(["id desc nulls"])).is_err());
+    }
+
+    #[test]
+    fn test_page_offset() {
+        assert_eq!(
+            rewrite("select p.title from post p", Order::new(), false, json!({"p": {"limit": "10", "offset": "20"}})),
+            "SELECT p.title FROM post p LIMIT 10 OFFSET 20",
+        );
+    }
+
+    #[test]
+    fn test_page_keyset() {
+        assert_eq!(
+            rewrite(
+                "select p.title from post p where p.published",
+                order(json!({"p": {"at": "desc", "id": "desc"}})),
+                false,
+                json!({"p": {"limit": 10, "after": ["2024-01-01", "5"]}}),
+            ),
+            r#"SELECT p.title FROM post p WHERE (p.published) AND (p."at", p."id") < ('2024-01-01', '5') ORDER BY p."at" DESC, p."id" DESC LIMIT 10"#,
+        );
+        assert_eq!(
+            rewrite(
+                "select p.title from post p",
+                order(json!({"p": {"at": "desc", "id": "asc"}})),
+                false,
+                json!({"p": {"after": ["2024-01-01", "5"]}}),
+            ),
+            r#"SELECT p.title FROM post p WHERE p."at" < '2024-01-01' OR (p."at" = '2024-01-01' AND p."id" > '5') ORDER BY p."at" DESC, p."id" ASC"#,
+        );
+    }
+
+    #[test]
+    fn test_count_base() {
+        let page = BTreeMap::from([("p".to_string(), Page { limit: Some(10), ..Default::default() })]);
+        let statements = Parser::parse_sql(&PostgreSqlDialect{}, "select string_agg(x.title, '') from (select p.title from post p order by p.id limit 5) x").unwrap();
+
+        let mut count = CountBase { page: Some(&page), base: None };
+        let _ = Visit::visit(&statements, &mut count);
+        assert_eq!(count.sql().unwrap(), "SELECT p.title FROM post p");
+
+        let mut count = CountBase { page: None, base: None };
+        let _ = Visit::visit(&statements, &mut count);
+        assert_eq!(count.sql().unwrap(), "SELECT string_agg(x.title, '') FROM (SELECT p.title FROM post p ORDER BY p.id LIMIT 5) x");
+    }
+
+    #[test]
+    fn test_keyset_base() {
+        let order = order(json!({"p": ["at desc", "id"]}));
+        let statements = Parser::parse_sql(&PostgreSqlDialect{}, r#"select string_agg(x.title, '') from (select p.title from post p where (p."at", p."id") < ('2024-01-01', '5') order by p."at" desc, p."id" limit 5) x"#).unwrap();
+
+        let mut keyset = KeysetBase { rel: "p", keys: order.get("p").unwrap(), base: None };
+        let _ = Visit::visit(&statements, &mut keyset);
+        assert_eq!(keyset.sql().unwrap(), r#"SELECT p."at"::TEXT, p."id"::TEXT FROM post p WHERE (p."at", p."id") < ('2024-01-01', '5') ORDER BY p."at" DESC, p."id" LIMIT 5"#);
+    }
 
+    #[test]
+    fn test_set_local_only() {
+        assert!(set_local_only("set local role to web; set local \"request.user\" = 'a'; set local time zone 'UTC'").is_ok());
+        assert!(set_local_only("set role web").is_err());
+        assert!(set_local_only("set session statement_timeout = 0").is_err());
+        assert!(set_local_only("set local role web; create temp table t ()").is_err());
+        assert!(set_local_only("prepare p as select 1").is_err());
+        assert!(set_local_only("set local role web; -- ; set role web").is_ok());
+        assert!(set_local_only("set local x = ';'; set role web").is_err());
+    }
+
+    #[test]
+    fn test_explainable() {
+        assert!(explainable("select 1"));
+        assert!(explainable("with x as (select 1) select * from x"));
+        assert!(!explainable("call refresh()"));
+        assert!(!explainable("select 1; select 2"));
+    }
+}